@@ -0,0 +1,476 @@
+use std::{fmt::Display, time::Duration};
+
+use bytes::{Buf, Bytes};
+use tokio::io::AsyncWriteExt;
+use tracing::Instrument;
+
+use crate::{Error, ResponseResult, Result};
+
+/// Sample rates supported by the Opus encoder.
+const OPUS_SAMPLE_RATES: [u32; 5] = [8000, 12000, 16000, 24000, 48000];
+
+/// An output format that can be requested for any mode, transcoded with ffmpeg if needed.
+#[derive(serde::Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Format {
+    Wav,
+    Mp3,
+    OggOpus,
+    OggVorbis,
+    /// Headerless signed 16-bit little endian samples.
+    Pcm,
+}
+
+impl Format {
+    pub const fn content_type(self) -> &'static str {
+        match self {
+            Self::Wav => "audio/wav",
+            Self::Mp3 => "audio/mpeg",
+            Self::OggOpus => "audio/ogg; codecs=opus",
+            Self::OggVorbis => "audio/ogg; codecs=vorbis",
+            Self::Pcm => "audio/pcm",
+        }
+    }
+
+    /// Identifies the format of provider audio from its content type.
+    fn from_content_type(content_type: &str) -> Option<Self> {
+        match essence(content_type) {
+            "audio/wav" => Some(Self::Wav),
+            "audio/mpeg" => Some(Self::Mp3),
+            "audio/opus" => Some(Self::OggOpus),
+            "audio/ogg" => Some(Self::OggVorbis),
+            "audio/pcm" => Some(Self::Pcm),
+            _ => None,
+        }
+    }
+
+    const fn ffmpeg_args(self) -> &'static [&'static str] {
+        match self {
+            Self::Wav => &["-f", "wav", "-c:a", "pcm_s16le"],
+            Self::Mp3 => &["-f", "mp3", "-c:a", "libmp3lame"],
+            Self::OggOpus => &["-f", "ogg", "-c:a", "libopus"],
+            Self::OggVorbis => &["-f", "ogg", "-c:a", "libvorbis"],
+            Self::Pcm => &["-f", "s16le", "-c:a", "pcm_s16le"],
+        }
+    }
+}
+
+impl Display for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Wav => "wav",
+            Self::Mp3 => "mp3",
+            Self::OggOpus => "ogg-opus",
+            Self::OggVorbis => "ogg-vorbis",
+            Self::Pcm => "pcm",
+        })
+    }
+}
+
+/// The `format`, `sample_rate` and `channels` requested by the client.
+#[derive(Clone, Copy)]
+pub struct Output {
+    pub format: Format,
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u16>,
+}
+
+impl Output {
+    /// Validates the requested output, returning `None` if the provider's own format should be used.
+    pub fn new(
+        format: Option<Format>,
+        sample_rate: Option<u32>,
+        channels: Option<u16>,
+        preferred_format: Option<&str>,
+    ) -> ResponseResult<Option<Self>> {
+        let Some(format) = format else {
+            if sample_rate.is_some() || channels.is_some() {
+                return Err(Error::InvalidFormat(String::from(
+                    "sample_rate and channels require format to be set",
+                )));
+            }
+
+            return Ok(None);
+        };
+
+        if preferred_format.is_some() {
+            return Err(Error::InvalidFormat(String::from(
+                "format and preferred_format cannot be used together",
+            )));
+        }
+
+        if let Some(sample_rate) = sample_rate {
+            let valid = if format == Format::OggOpus {
+                OPUS_SAMPLE_RATES.contains(&sample_rate)
+            } else {
+                (8000..=48000).contains(&sample_rate)
+            };
+
+            if !valid {
+                return Err(Error::InvalidFormat(format!(
+                    "sample_rate of {sample_rate} is not supported for {format}"
+                )));
+            }
+        } else if format == Format::Pcm {
+            return Err(Error::InvalidFormat(String::from(
+                "sample_rate must be set for pcm",
+            )));
+        }
+
+        if channels.is_some_and(|channels| !(1..=2).contains(&channels)) {
+            return Err(Error::InvalidFormat(String::from(
+                "channels must be 1 or 2",
+            )));
+        }
+
+        Ok(Some(Self {
+            format,
+            sample_rate,
+            channels,
+        }))
+    }
+
+    /// The content type of the output, including the layout of raw PCM.
+    pub fn content_type(&self) -> String {
+        if self.format == Format::Pcm {
+            let sample_rate = self.sample_rate.unwrap_or_default();
+            let channels = self.channels.unwrap_or(1);
+            format!("audio/pcm; rate={sample_rate}; channels={channels}")
+        } else {
+            self.format.content_type().to_owned()
+        }
+    }
+
+    /// Checks if provider audio of `content_type` can be returned as-is.
+    pub fn matches(&self, content_type: &str) -> bool {
+        self.sample_rate.is_none()
+            && self.channels.is_none()
+            && Format::from_content_type(content_type) == Some(self.format)
+    }
+}
+
+impl Display for Output {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.format)?;
+        if let Some(sample_rate) = self.sample_rate {
+            write!(f, " {sample_rate}Hz")?;
+        }
+        if let Some(channels) = self.channels {
+            write!(f, " {channels}ch")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks the `ffmpeg` binary used for transcoding is installed.
+pub fn check_ready() -> Result<()> {
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    if std::env::split_paths(&path).any(|dir| dir.join("ffmpeg").is_file()) {
+        Ok(())
+    } else {
+        anyhow::bail!("ffmpeg could not be found in PATH")
+    }
+}
+
+/// The content type without any parameters, eg. `audio/pcm` for `audio/pcm; rate=16000`.
+fn essence(content_type: &str) -> &str {
+    content_type.split(';').next().unwrap_or_default().trim()
+}
+
+fn content_type_param<'a>(content_type: &'a str, name: &str) -> Result<&'a str> {
+    content_type
+        .split(';')
+        .filter_map(|part| part.trim().split_once('='))
+        .find_map(|(key, value)| (key == name).then_some(value))
+        .ok_or_else(|| anyhow::anyhow!("{content_type} is missing the {name} parameter"))
+}
+
+/// Arguments for ffmpeg to read audio of `content_type` from stdin.
+fn ffmpeg_input(content_type: &str) -> Result<Vec<String>> {
+    // Raw formats have no header to detect the layout from.
+    let raw_format = match essence(content_type) {
+        "audio/pcm" => Some((
+            "s16le",
+            content_type_param(content_type, "rate")?,
+            content_type_param(content_type, "channels")?,
+        )),
+        _ => None,
+    };
+
+    let mut args = Vec::new();
+    if let Some((format, sample_rate, channels)) = raw_format {
+        args.extend(["-f", format, "-ar", sample_rate, "-ac", channels]);
+    }
+
+    args.extend(["-i", "pipe:0"]);
+    Ok(args.into_iter().map(String::from).collect())
+}
+
+/// Arguments for ffmpeg to write audio of `content_type` to stdout, copying the stream unless `reencode` is set.
+fn ffmpeg_output(content_type: &str, audio: &[u8], reencode: bool) -> Result<[&'static str; 4]> {
+    let (format, codec) = match essence(content_type) {
+        "audio/mpeg" => ("mp3", "libmp3lame"),
+        "audio/wav" => ("wav", "pcm_s16le"),
+        "audio/ogg" | "audio/opus" if is_opus(audio) => ("ogg", "libopus"),
+        "audio/ogg" => ("ogg", "libvorbis"),
+        "audio/pcm" => ("s16le", "pcm_s16le"),
+        _ => anyhow::bail!("Unknown content type: {content_type}"),
+    };
+
+    Ok(["-f", format, "-c:a", if reencode { codec } else { "copy" }])
+}
+
+/// The start of the first packet of an Ogg stream, which identifies the codec.
+fn ogg_first_packet(audio: &[u8]) -> Option<&[u8]> {
+    let segment_count = usize::from(*audio.get(26)?);
+    audio.get(27 + segment_count..)
+}
+
+fn is_opus(audio: &[u8]) -> bool {
+    ogg_first_packet(audio).is_some_and(|packet| packet.starts_with(b"OpusHead"))
+}
+
+/// The sample rate of the audio, if it can be found from the header.
+fn sample_rate(audio: &[u8], content_type: &str) -> Option<u32> {
+    match essence(content_type) {
+        "audio/mpeg" => crate::mp3::sample_rate(audio),
+        "audio/wav" => crate::wav::parse(audio).ok().map(|wav| wav.sample_rate),
+        // Opus is always decoded at 48 kHz, whatever the input was.
+        "audio/ogg" | "audio/opus" if is_opus(audio) => Some(48000),
+        "audio/ogg" => ogg_first_packet(audio)
+            .filter(|packet| packet.starts_with(b"\x01vorbis"))
+            .and_then(|packet| packet.get(12..16))
+            .map(|rate| u32::from_le_bytes(rate.try_into().unwrap())),
+        "audio/pcm" => content_type_param(content_type, "rate").ok()?.parse().ok(),
+        _ => None,
+    }
+}
+
+/// An ffmpeg filter trimming silence quieter than `threshold` dBFS from both ends, keeping `padding` seconds of it.
+///
+/// silenceremove's stop options also cut pauses mid-speech, so the end is trimmed by reversing the audio instead.
+pub fn trim_silence_filter(threshold: f64, padding: f64) -> String {
+    let trim_start = format!(
+        "silenceremove=start_periods=1:start_threshold={threshold}dB:start_silence={padding}"
+    );
+
+    format!("{trim_start},areverse,{trim_start},areverse")
+}
+
+/// The range of integrated loudness targets ffmpeg's loudnorm filter accepts, in LUFS.
+const LOUDNESS_RANGE: std::ops::RangeInclusive<f64> = -70.0..=-5.0;
+
+pub fn check_loudness(target: f64) -> Result<()> {
+    if !LOUDNESS_RANGE.contains(&target) {
+        anyhow::bail!(
+            "must be between {} and {} LUFS, not {target}",
+            LOUDNESS_RANGE.start(),
+            LOUDNESS_RANGE.end()
+        );
+    }
+
+    Ok(())
+}
+
+/// Target true peak in dBTP when normalizing loudness, leaving headroom for lossy encoding.
+const TRUE_PEAK: f64 = -1.5;
+
+/// An ffmpeg filter normalizing loudness to `target` LUFS as per EBU R128, limiting peaks to [`TRUE_PEAK`].
+pub fn loudness_filter(target: f64) -> String {
+    format!("loudnorm=I={target}:TP={TRUE_PEAK}:LRA=11")
+}
+
+/// Pipes `audio` through ffmpeg with the given arguments, returning its output.
+async fn ffmpeg(audio: Bytes, args: Vec<String>) -> Result<Bytes> {
+    let mut child = tokio::process::Command::new("ffmpeg")
+        .kill_on_drop(true)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .args(["-hide_banner", "-loglevel", "error"])
+        .args(args)
+        .spawn()?;
+
+    let mut stdin = child.stdin.take().expect("Failed to open ffmpeg stdin");
+
+    // Written concurrently with reading the output, as ffmpeg may fill the stdout pipe first.
+    let write = async move {
+        let result = stdin.write_all(&audio).await;
+        drop(stdin);
+        result
+    };
+
+    let (write_result, output) = tokio::join!(write, child.wait_with_output());
+    let output = output?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!("ffmpeg failed with {}: {}", output.status, stderr.trim());
+    }
+
+    write_result?;
+
+    let mut audio = output.stdout;
+    if audio.starts_with(b"RIFF") {
+        // ffmpeg cannot seek back to fill in the sizes when writing to a pipe.
+        crate::wav::fix_sizes(&mut audio)?;
+    }
+
+    Ok(Bytes::from(audio))
+}
+
+/// Runs `filters` over audio of `content_type`, converting it into the requested output if given.
+///
+/// Without an output, the audio is re-encoded in its original format.
+pub async fn transcode(
+    audio: Bytes,
+    content_type: &str,
+    output: Option<Output>,
+    filters: &[String],
+) -> Result<Bytes> {
+    let mut args = ffmpeg_input(content_type)?;
+    if !filters.is_empty() {
+        args.extend([String::from("-af"), filters.join(",")]);
+    }
+
+    // Bitexact stops ffmpeg adding metadata chunks to the output.
+    args.extend(["-map_metadata", "-1", "-fflags", "+bitexact"].map(String::from));
+
+    // Filters such as loudnorm resample internally, so the original rate has to be restored.
+    let sample_rate = output.and_then(|output| output.sample_rate).or_else(|| {
+        (!filters.is_empty())
+            .then(|| sample_rate(&audio, content_type))
+            .flatten()
+    });
+
+    if let Some(sample_rate) = sample_rate {
+        args.extend([String::from("-ar"), sample_rate.to_string()]);
+    }
+    if let Some(channels) = output.and_then(|output| output.channels) {
+        args.extend([String::from("-ac"), channels.to_string()]);
+    }
+
+    match output {
+        Some(output) => args.extend(output.format.ffmpeg_args().iter().map(|&a| a.to_owned())),
+        None => args.extend(ffmpeg_output(content_type, &audio, true)?.map(String::from)),
+    }
+
+    args.push(String::from("pipe:1"));
+
+    let span = tracing::info_span!(
+        "transcode",
+        output = output.map(|output| output.to_string()),
+        filters = filters.join(","),
+    );
+
+    ffmpeg(audio, args).instrument(span).await
+}
+
+/// Cuts audio of `content_type` down to `max_length`, at a frame or sample boundary.
+///
+/// The stream is copied as-is unless `fade_out` is set, as fading requires re-encoding.
+pub async fn truncate(
+    audio: Bytes,
+    content_type: &str,
+    max_length: Duration,
+    fade_out: Option<Duration>,
+) -> Result<Bytes> {
+    let output_args = ffmpeg_output(content_type, &audio, fade_out.is_some())?;
+
+    let mut args = ffmpeg_input(content_type)?;
+    args.extend([String::from("-t"), max_length.as_secs_f64().to_string()]);
+    if let Some(fade_out) = fade_out {
+        let fade_out = fade_out.min(max_length);
+        let start = max_length.saturating_sub(fade_out).as_secs_f64();
+        let fade = format!("afade=t=out:st={start}:d={}", fade_out.as_secs_f64());
+        args.extend([String::from("-af"), fade]);
+    }
+
+    args.extend(["-map_metadata", "-1", "-fflags", "+bitexact"].map(String::from));
+    args.extend(output_args.map(String::from));
+    args.push(String::from("pipe:1"));
+
+    ffmpeg(audio, args)
+        .instrument(tracing::info_span!("truncate", max_length = ?max_length))
+        .await
+}
+
+/// Works out the duration of audio from its content type, as returned by [`Output::content_type`]
+/// or [`crate::TTSMode::content_type`].
+pub fn duration(audio: &[u8], content_type: &str) -> Result<Duration> {
+    match essence(content_type) {
+        "audio/mpeg" => Ok(mp3_duration::from_read(&mut audio.reader())?),
+        // Google also returns mu-law and A-law in a WAV container.
+        "audio/wav" => crate::wav::parse(audio)?.duration(),
+        "audio/ogg" | "audio/opus" => ogg_duration(audio),
+        "audio/pcm" => {
+            let sample_rate: u32 = content_type_param(content_type, "rate")?.parse()?;
+            let channels: u32 = content_type_param(content_type, "channels")?.parse()?;
+            Ok(bytes_duration(audio.len(), sample_rate * channels * 2))
+        }
+        _ => anyhow::bail!("Unknown content type: {content_type}"),
+    }
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn bytes_duration(len: usize, byte_rate: u32) -> Duration {
+    Duration::from_secs_f64(len as f64 / f64::from(byte_rate))
+}
+
+/// Calculates the duration from the granule position of the last Ogg page, in samples.
+fn ogg_duration(audio: &[u8]) -> Result<Duration> {
+    const HEADER_LEN: usize = 27;
+
+    let mut offset = 0;
+    let mut first_packet = None;
+    let mut last_granule = None;
+    while let Some(header) = audio.get(offset..offset + HEADER_LEN) {
+        if &header[..4] != b"OggS" {
+            anyhow::bail!("Invalid Ogg page at offset {offset}");
+        }
+
+        let granule = u64::from_le_bytes(header[6..14].try_into().unwrap());
+        let segment_count = usize::from(header[26]);
+        let segments = audio
+            .get(offset + HEADER_LEN..offset + HEADER_LEN + segment_count)
+            .ok_or_else(|| anyhow::anyhow!("Ogg page at offset {offset} is truncated"))?;
+
+        let data_start = offset + HEADER_LEN + segment_count;
+        let data_len: usize = segments.iter().copied().map(usize::from).sum();
+        first_packet.get_or_insert(data_start..data_start + data_len);
+
+        // Pages without a finished packet have a granule position of -1.
+        if granule != u64::MAX {
+            last_granule = Some(granule);
+        }
+
+        offset = data_start + data_len;
+    }
+
+    let first_packet = first_packet
+        .and_then(|range| audio.get(range))
+        .ok_or_else(|| anyhow::anyhow!("Ogg stream is empty or truncated"))?;
+
+    let granule = last_granule.unwrap_or_default();
+    let samples_per_sec: u64;
+    let samples = if first_packet.starts_with(b"OpusHead") && first_packet.len() >= 12 {
+        // Opus granules are always at 48 kHz, and include the decoder's pre-skip.
+        samples_per_sec = 48000;
+        let pre_skip = u16::from_le_bytes(first_packet[10..12].try_into().unwrap());
+        granule.saturating_sub(u64::from(pre_skip))
+    } else if first_packet.starts_with(b"\x01vorbis") && first_packet.len() >= 16 {
+        samples_per_sec = u32::from_le_bytes(first_packet[12..16].try_into().unwrap()).into();
+        granule
+    } else {
+        anyhow::bail!("Ogg stream is not Opus or Vorbis");
+    };
+
+    if samples_per_sec == 0 {
+        anyhow::bail!("Ogg stream has a sample rate of 0");
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    Ok(Duration::from_secs_f64(
+        samples as f64 / samples_per_sec as f64,
+    ))
+}
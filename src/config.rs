@@ -0,0 +1,623 @@
+use std::{collections::BTreeMap, fmt::Display, path::Path, str::FromStr, time::Duration};
+
+use crate::{Result, TTSMode};
+
+/// The service configuration, loaded from an optional TOML file then overridden by env vars.
+///
+/// Durations are in seconds.
+#[derive(serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// A TCP address, or `unix:{path}` to listen on a Unix domain socket.
+    pub bind_addr: String,
+    pub tls: Option<TlsConfig>,
+    pub auth_key: Option<String>,
+    pub shutdown_grace_period: f64,
+
+    pub log: LogConfig,
+    pub cache: Option<CacheConfig>,
+    pub limits: LimitsConfig,
+    pub silence: SilenceConfig,
+
+    pub gtts: GttsConfig,
+    pub polly: PollyConfig,
+    pub espeak: EspeakConfig,
+    pub gcloud: GcloudConfig,
+}
+
+/// PEM certificate chain and private key, reloaded when either file changes.
+#[derive(Clone, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert_path: String,
+    pub key_path: String,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// A level, or per-module filters in `EnvFilter` syntax.
+    pub level: String,
+    /// Either `text` or `json`.
+    pub format: String,
+    pub redact_text: bool,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CacheConfig {
+    pub redis_uri: String,
+    /// Fernet key used to encrypt the cached audio.
+    pub key: String,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub synthesis_timeout: f64,
+    pub queue_size: usize,
+    pub queue_timeout: f64,
+    /// Estimate the audio length from the text before synthesis, to reject or truncate it early.
+    pub estimate_length: bool,
+    /// How far over `max_length` the estimate must be before acting on it, as a fraction.
+    pub estimate_margin: f64,
+}
+
+/// How silence is detected when trimming it from the start and end of audio.
+#[derive(serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SilenceConfig {
+    /// Level in dBFS below which audio is considered silent.
+    pub threshold: f64,
+    /// Silence to keep at either end after trimming.
+    pub padding: f64,
+}
+
+/// Settings every mode has, flattened into each mode's section.
+#[derive(serde::Deserialize)]
+#[serde(default)]
+pub struct ModeConfig {
+    pub enabled: bool,
+    /// Overrides `limits.synthesis_timeout` for the mode.
+    pub timeout: Option<f64>,
+    /// Target loudness in LUFS to normalize audio to, disabled if unset.
+    pub loudness: Option<f64>,
+    /// Trim leading and trailing silence, as configured by [`SilenceConfig`].
+    pub trim_silence: bool,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GttsConfig {
+    #[serde(flatten)]
+    pub mode: ModeConfig,
+    pub max_concurrency: Option<usize>,
+    /// How many chunks of a single message to fetch at once.
+    pub chunk_concurrency: usize,
+    /// Block of IPv6 addresses to rotate through when rate limited, disabled if unset.
+    pub ipv6_block: Option<String>,
+    /// How many unblocked addresses from `ipv6_block` to keep ready.
+    pub ip_pool_size: usize,
+    /// How long an address is rested after being blocked.
+    pub ip_cooldown: f64,
+    pub ip_selection: IpSelection,
+}
+
+/// How the gTTS address pool picks the address for each request.
+#[derive(Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IpSelection {
+    RoundRobin,
+    /// Prefers addresses that have never been blocked, then those blocked longest ago.
+    LeastRecentlyBlocked,
+}
+
+impl FromStr for IpSelection {
+    type Err = String;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        match value {
+            "round-robin" => Ok(Self::RoundRobin),
+            "least-recently-blocked" => Ok(Self::LeastRecentlyBlocked),
+            _ => Err(String::from(
+                "must be \"round-robin\" or \"least-recently-blocked\"",
+            )),
+        }
+    }
+}
+
+#[derive(Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PollyConfig {
+    #[serde(flatten)]
+    pub mode: ModeConfig,
+    pub max_concurrency: Option<usize>,
+}
+
+#[derive(Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EspeakConfig {
+    #[serde(flatten)]
+    pub mode: ModeConfig,
+    /// Defaults to the number of CPUs.
+    pub workers: Option<usize>,
+}
+
+#[derive(Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GcloudConfig {
+    #[serde(flatten)]
+    pub mode: ModeConfig,
+    pub max_concurrency: Option<usize>,
+    /// Path to the service account JSON.
+    pub credentials: Option<String>,
+    /// Caps on concurrent requests per voice family, such as `Studio`, on top of `max_concurrency`.
+    pub family_max_concurrency: FamilyConcurrency,
+}
+
+/// Concurrency limits keyed by gCloud voice family, set in env vars as `Studio=2,Journey=4`.
+#[derive(Clone, Default, serde::Deserialize)]
+#[serde(transparent)]
+pub struct FamilyConcurrency(pub BTreeMap<String, usize>);
+
+impl FromStr for FamilyConcurrency {
+    type Err = String;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        value
+            .split(',')
+            .filter(|entry| !entry.trim().is_empty())
+            .map(|entry| {
+                let (family, limit) = entry
+                    .split_once('=')
+                    .ok_or_else(|| format!("{entry:?} is not in the form Family=limit"))?;
+
+                let limit = limit
+                    .trim()
+                    .parse()
+                    .map_err(|err| format!("limit for {family}: {err}"))?;
+
+                Ok((family.trim().to_owned(), limit))
+            })
+            .collect::<std::result::Result<_, _>>()
+            .map(Self)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind_addr: String::from("0.0.0.0:3000"),
+            tls: None,
+            auth_key: None,
+            shutdown_grace_period: 30.0,
+            log: LogConfig::default(),
+            cache: None,
+            limits: LimitsConfig::default(),
+            silence: SilenceConfig::default(),
+            gtts: GttsConfig::default(),
+            polly: PollyConfig::default(),
+            espeak: EspeakConfig::default(),
+            gcloud: GcloudConfig::default(),
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: String::from("INFO"),
+            format: String::from("text"),
+            redact_text: false,
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            synthesis_timeout: 30.0,
+            queue_size: 64,
+            queue_timeout: 10.0,
+            estimate_length: false,
+            estimate_margin: 0.25,
+        }
+    }
+}
+
+impl Default for ModeConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            timeout: None,
+            loudness: None,
+            trim_silence: false,
+        }
+    }
+}
+
+impl ModeConfig {
+    fn apply_env(&mut self, mode: TTSMode, problems: &mut Problems) {
+        let prefix = mode.env_prefix();
+        problems.env(&format!("{prefix}_ENABLED"), &mut self.enabled);
+        problems.env_opt(&format!("{prefix}_TIMEOUT"), &mut self.timeout);
+        problems.env_opt(&format!("{prefix}_LOUDNESS"), &mut self.loudness);
+        problems.env(&format!("{prefix}_TRIM_SILENCE"), &mut self.trim_silence);
+    }
+}
+
+impl GttsConfig {
+    fn validate(&self, problems: &mut Problems) {
+        problems.check_count("gtts.chunk_concurrency", self.chunk_concurrency);
+        problems.check_count("gtts.ip_pool_size", self.ip_pool_size);
+        problems.check_secs("gtts.ip_cooldown", self.ip_cooldown);
+
+        if let Some(ip_block) = &self.ipv6_block {
+            if let Err(err) = ip_block.parse::<ipnetwork::IpNetwork>() {
+                problems.push(format!("gtts.ipv6_block: {err}"));
+            }
+        }
+    }
+}
+
+impl Default for SilenceConfig {
+    fn default() -> Self {
+        Self {
+            threshold: -50.0,
+            padding: 0.05,
+        }
+    }
+}
+
+impl Default for GttsConfig {
+    fn default() -> Self {
+        Self {
+            mode: ModeConfig::default(),
+            max_concurrency: None,
+            chunk_concurrency: 4,
+            ipv6_block: None,
+            ip_pool_size: 4,
+            ip_cooldown: 600.0,
+            ip_selection: IpSelection::RoundRobin,
+        }
+    }
+}
+
+/// Collects every problem with the config, so they can be reported at once.
+#[derive(Default)]
+struct Problems(Vec<String>);
+
+impl Problems {
+    fn push(&mut self, problem: impl Into<String>) {
+        self.0.push(problem.into());
+    }
+
+    fn parse_env<T: FromStr>(&mut self, var: &str) -> Option<T>
+    where
+        T::Err: Display,
+    {
+        let value = std::env::var(var).ok()?;
+        match value.parse() {
+            Ok(value) => Some(value),
+            Err(err) => {
+                self.push(format!("{var}: could not parse {value:?}: {err}"));
+                None
+            }
+        }
+    }
+
+    fn env<T: FromStr>(&mut self, var: &str, target: &mut T)
+    where
+        T::Err: Display,
+    {
+        if let Some(value) = self.parse_env(var) {
+            *target = value;
+        }
+    }
+
+    fn env_opt<T: FromStr>(&mut self, var: &str, target: &mut Option<T>)
+    where
+        T::Err: Display,
+    {
+        if let Some(value) = self.parse_env(var) {
+            *target = Some(value);
+        }
+    }
+
+    fn check_secs(&mut self, name: &str, secs: f64) {
+        if !(secs.is_finite() && secs > 0.0) {
+            self.push(format!(
+                "{name}: must be a positive number of seconds, not {secs}"
+            ));
+        }
+    }
+
+    fn check_count(&mut self, name: &str, count: usize) {
+        if count == 0 {
+            self.push(format!("{name}: must be greater than 0"));
+        }
+    }
+}
+
+impl Config {
+    /// Loads the config file at `path` if given, applies env var overrides, then validates it.
+    ///
+    /// # Errors
+    /// Returns an error listing every problem found.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let mut config: Self = match path {
+            Some(path) => {
+                let raw = std::fs::read_to_string(path)
+                    .map_err(|err| anyhow::anyhow!("Could not read {}: {err}", path.display()))?;
+
+                toml::from_str(&raw)
+                    .map_err(|err| anyhow::anyhow!("Could not parse {}: {err}", path.display()))?
+            }
+            None => Self::default(),
+        };
+
+        let mut problems = Problems::default();
+        config.apply_env(&mut problems);
+        config.validate(&mut problems);
+
+        if problems.0.is_empty() {
+            Ok(config)
+        } else {
+            let mut message = String::from("Invalid configuration:");
+            for problem in problems.0 {
+                message.push_str("\n- ");
+                message.push_str(&problem);
+            }
+
+            Err(anyhow::Error::msg(message))
+        }
+    }
+
+    fn apply_env(&mut self, problems: &mut Problems) {
+        problems.env("BIND_ADDR", &mut self.bind_addr);
+        problems.env_opt("AUTH_KEY", &mut self.auth_key);
+        problems.env("SHUTDOWN_GRACE_PERIOD", &mut self.shutdown_grace_period);
+
+        let tls_cert_path = std::env::var("TLS_CERT_PATH").ok();
+        let tls_key_path = std::env::var("TLS_KEY_PATH").ok();
+        if tls_cert_path.is_some() || tls_key_path.is_some() {
+            let tls = self.tls.get_or_insert_with(|| TlsConfig {
+                cert_path: String::new(),
+                key_path: String::new(),
+            });
+
+            if let Some(cert_path) = tls_cert_path {
+                tls.cert_path = cert_path;
+            }
+            if let Some(key_path) = tls_key_path {
+                tls.key_path = key_path;
+            }
+        }
+
+        problems.env("LOG_LEVEL", &mut self.log.level);
+        problems.env("LOG_FORMAT", &mut self.log.format);
+        problems.env("LOG_REDACT_TEXT", &mut self.log.redact_text);
+
+        match (std::env::var("REDIS_URI"), &mut self.cache) {
+            (Ok(redis_uri), Some(cache)) => cache.redis_uri = redis_uri,
+            (Ok(redis_uri), cache @ None) => {
+                *cache = Some(CacheConfig {
+                    redis_uri,
+                    key: String::new(),
+                });
+            }
+            (Err(_), _) => {}
+        }
+
+        if let Some(cache) = &mut self.cache {
+            problems.env("CACHE_KEY", &mut cache.key);
+        }
+
+        problems.env("SYNTHESIS_TIMEOUT", &mut self.limits.synthesis_timeout);
+        problems.env("QUEUE_SIZE", &mut self.limits.queue_size);
+        problems.env("QUEUE_TIMEOUT", &mut self.limits.queue_timeout);
+        problems.env("ESTIMATE_LENGTH", &mut self.limits.estimate_length);
+        problems.env("ESTIMATE_MARGIN", &mut self.limits.estimate_margin);
+
+        problems.env("SILENCE_THRESHOLD", &mut self.silence.threshold);
+        problems.env("SILENCE_PADDING", &mut self.silence.padding);
+
+        self.gtts.mode.apply_env(TTSMode::gTTS, problems);
+        problems.env_opt("GTTS_MAX_CONCURRENCY", &mut self.gtts.max_concurrency);
+        problems.env("GTTS_CHUNK_CONCURRENCY", &mut self.gtts.chunk_concurrency);
+        match std::env::var("IPV6_BLOCK").as_deref() {
+            Ok("DISABLE") => self.gtts.ipv6_block = None,
+            Ok(ip_block) => self.gtts.ipv6_block = Some(ip_block.to_owned()),
+            Err(_) => {}
+        }
+        problems.env("GTTS_IP_POOL_SIZE", &mut self.gtts.ip_pool_size);
+        problems.env("GTTS_IP_COOLDOWN", &mut self.gtts.ip_cooldown);
+        problems.env("GTTS_IP_SELECTION", &mut self.gtts.ip_selection);
+
+        self.polly.mode.apply_env(TTSMode::Polly, problems);
+        problems.env_opt("POLLY_MAX_CONCURRENCY", &mut self.polly.max_concurrency);
+
+        self.espeak.mode.apply_env(TTSMode::eSpeak, problems);
+        problems.env_opt("ESPEAK_WORKERS", &mut self.espeak.workers);
+
+        self.gcloud.mode.apply_env(TTSMode::gCloud, problems);
+        problems.env_opt("GCLOUD_MAX_CONCURRENCY", &mut self.gcloud.max_concurrency);
+        problems.env(
+            "GCLOUD_FAMILY_MAX_CONCURRENCY",
+            &mut self.gcloud.family_max_concurrency,
+        );
+        problems.env_opt(
+            "GOOGLE_APPLICATION_CREDENTIALS",
+            &mut self.gcloud.credentials,
+        );
+    }
+
+    fn validate(&self, problems: &mut Problems) {
+        self.validate_bind_addr(problems);
+
+        if let Some(tls) = &self.tls {
+            if tls.cert_path.is_empty() || tls.key_path.is_empty() {
+                problems.push("tls: both cert_path and key_path must be set");
+            } else if let Err(err) = crate::server::load_certified_key(tls) {
+                problems.push(format!("tls: {err:#}"));
+            }
+        }
+
+        problems.check_secs("shutdown_grace_period", self.shutdown_grace_period);
+
+        if let Err(err) = tracing_subscriber::EnvFilter::try_new(&self.log.level) {
+            problems.push(format!("log.level: {err}"));
+        }
+
+        if !["text", "json"].contains(&self.log.format.as_str()) {
+            problems.push(format!(
+                "log.format: must be \"text\" or \"json\", not {:?}",
+                self.log.format
+            ));
+        }
+
+        if let Some(cache) = &self.cache {
+            if let Err(err) =
+                deadpool_redis::redis::parse_redis_url(&cache.redis_uri).ok_or("invalid redis URI")
+            {
+                problems.push(format!("cache.redis_uri: {err}"));
+            }
+
+            if cache.key.is_empty() {
+                problems.push("cache.key: must be set when the cache is enabled");
+            } else if fernet::Fernet::new(&cache.key).is_none() {
+                problems.push("cache.key: is not a valid Fernet key");
+            }
+        }
+
+        problems.check_secs("limits.synthesis_timeout", self.limits.synthesis_timeout);
+        problems.check_secs("limits.queue_timeout", self.limits.queue_timeout);
+        if !(self.limits.estimate_margin.is_finite() && self.limits.estimate_margin >= 0.0) {
+            problems.push(format!(
+                "limits.estimate_margin: must be a non-negative fraction, not {}",
+                self.limits.estimate_margin
+            ));
+        }
+
+        if !(self.silence.threshold.is_finite() && self.silence.threshold <= 0.0) {
+            problems.push(format!(
+                "silence.threshold: must be a level in dBFS of 0 or below, not {}",
+                self.silence.threshold
+            ));
+        }
+
+        if !(self.silence.padding.is_finite() && self.silence.padding >= 0.0) {
+            problems.push(format!(
+                "silence.padding: must be a non-negative number of seconds, not {}",
+                self.silence.padding
+            ));
+        }
+
+        for mode in [
+            TTSMode::gTTS,
+            TTSMode::Polly,
+            TTSMode::eSpeak,
+            TTSMode::gCloud,
+        ] {
+            let section = mode.env_prefix().to_lowercase();
+            let mode = self.mode(mode);
+            if let Some(timeout) = mode.timeout {
+                problems.check_secs(&format!("{section}.timeout"), timeout);
+            }
+
+            if let Some(loudness) = mode.loudness {
+                if let Err(err) = crate::audio::check_loudness(loudness) {
+                    problems.push(format!("{section}.loudness: {err}"));
+                }
+            }
+        }
+
+        for (name, count) in [
+            ("gtts.max_concurrency", self.gtts.max_concurrency),
+            ("polly.max_concurrency", self.polly.max_concurrency),
+            ("espeak.workers", self.espeak.workers),
+            ("gcloud.max_concurrency", self.gcloud.max_concurrency),
+        ] {
+            if let Some(count) = count {
+                problems.check_count(name, count);
+            }
+        }
+
+        self.gtts.validate(problems);
+        for (family, &count) in &self.gcloud.family_max_concurrency.0 {
+            let name = format!("gcloud.family_max_concurrency.{family}");
+            if !crate::gcloud::is_family(family) {
+                problems.push(format!(
+                    "{name}: unknown voice family, expected one of {}",
+                    crate::gcloud::family_names()
+                ));
+            }
+            problems.check_count(&name, count);
+        }
+
+        if self.gcloud.mode.enabled {
+            match &self.gcloud.credentials {
+                Some(path) => {
+                    if let Err(err) = crate::gcloud::ServiceAccount::load(Path::new(path)) {
+                        problems.push(format!("gcloud.credentials: {err:#}"));
+                    }
+                }
+                None => problems.push("gcloud.credentials: must be set when gCloud is enabled"),
+            }
+        }
+    }
+
+    fn validate_bind_addr(&self, problems: &mut Problems) {
+        match self.bind_addr.strip_prefix("unix:") {
+            Some("") => problems.push("bind_addr: unix socket path must not be empty"),
+            Some(_) => {}
+            None => {
+                // Hostnames are resolved the same way as when binding, eg. `localhost:3000`.
+                match std::net::ToSocketAddrs::to_socket_addrs(&self.bind_addr) {
+                    Ok(mut addrs) => {
+                        if addrs.next().is_none() {
+                            problems
+                                .push(format!("bind_addr: {} has no addresses", self.bind_addr));
+                        }
+                    }
+                    Err(err) => problems.push(format!("bind_addr: {err}")),
+                }
+            }
+        }
+    }
+
+    /// The settings every mode has, from the mode's section.
+    pub const fn mode(&self, mode: TTSMode) -> &ModeConfig {
+        match mode {
+            TTSMode::gTTS => &self.gtts.mode,
+            TTSMode::Polly => &self.polly.mode,
+            TTSMode::eSpeak => &self.espeak.mode,
+            TTSMode::gCloud => &self.gcloud.mode,
+        }
+    }
+
+    pub fn timeout(&self, mode: TTSMode) -> Duration {
+        let secs = self
+            .mode(mode)
+            .timeout
+            .unwrap_or(self.limits.synthesis_timeout);
+
+        Duration::from_secs_f64(secs)
+    }
+
+    pub const fn enabled(&self, mode: TTSMode) -> bool {
+        self.mode(mode).enabled
+    }
+
+    pub const fn loudness(&self, mode: TTSMode) -> Option<f64> {
+        self.mode(mode).loudness
+    }
+
+    pub const fn trim_silence(&self, mode: TTSMode) -> bool {
+        self.mode(mode).trim_silence
+    }
+
+    pub fn shutdown_grace_period(&self) -> Duration {
+        Duration::from_secs_f64(self.shutdown_grace_period)
+    }
+}
@@ -2,9 +2,26 @@ use std::sync::OnceLock;
 
 use reqwest::header::HeaderValue;
 use tokio::io::AsyncReadExt;
+use tracing::Instrument;
 
 use crate::Result;
 
+/// Checks the `espeak` and `mbrola` binaries are installed, along with the mbrola voices.
+pub fn check_ready() -> Result<()> {
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    for binary in ["espeak", "mbrola"] {
+        if !std::env::split_paths(&path).any(|dir| dir.join(binary).is_file()) {
+            anyhow::bail!("{binary} could not be found in PATH");
+        }
+    }
+
+    if std::fs::read_dir("/usr/share/mbrola")?.next().is_none() {
+        anyhow::bail!("No mbrola voices are installed in /usr/share/mbrola");
+    }
+
+    Ok(())
+}
+
 pub async fn get_tts(
     text: &str,
     voice: &str,
@@ -17,87 +34,21 @@ pub async fn get_tts(
     // We have to loop due to random "unable to get .wav header" errors.
     let mut i = 1;
     let mut raw_wav = loop {
-        let espeak_process = tokio::process::Command::new("espeak")
-            .stdout(std::process::Stdio::piped())
-            .stderr(std::process::Stdio::piped())
-            .args([
-                "--pho",
-                "-q",
-                "-s",
-                &speaking_rate.to_string(),
-                "-v",
-                &format!("mb/mb-{voice}"),
-                text,
-            ])
-            .spawn()?;
-
-        let tokio::process::Child { stdout, stderr, .. } = espeak_process;
-
-        let espeak_stdout: std::process::Stdio =
-            stdout.expect("Failed to open espeak stdout").try_into()?;
-
-        let mut mbrola_process = tokio::process::Command::new("mbrola")
-            .stdout(std::process::Stdio::piped())
-            .stderr(std::process::Stdio::piped())
-            .stdin(espeak_stdout)
-            .args([
-                "-e",
-                &format!("/usr/share/mbrola/{voice}/{voice}"),
-                "-",
-                "-.wav",
-            ])
-            .spawn()?;
-
-        // Filter out some warning messages from mbrola that clutter logs
-        if let Some(mut mbrola_stderr) = mbrola_process.stderr.take() {
-            tokio::spawn(async move {
-                let mut buffer = Vec::new();
-                while let Ok(written_bytes) = mbrola_stderr.read_buf(&mut buffer).await {
-                    if written_bytes == 0 {
-                        break;
-                    }
-
-                    let current_msg =
-                        std::str::from_utf8(&buffer).unwrap_or("TTS Service Error: Invalid UTF8");
-                    if !current_msg.contains("unknown, replaced with ") {
-                        tracing::error!("Mbrola Error: {current_msg}");
-                    }
-
-                    buffer.clear();
-                }
-
-                tracing::debug!("mbrola_stderr watcher closed");
-            });
-        };
-
-        let output = mbrola_process.wait_with_output().await?;
-        if output.stdout.len() == 44 {
-            let mut espeak_stderr = stderr.expect("Unable to open espeak stderr");
+        let attempt = generate_wav(text, voice, speaking_rate)
+            .instrument(tracing::info_span!("espeak_attempt", attempt = i));
 
-            let mut stderr = Vec::new();
-            espeak_stderr.read_to_end(&mut stderr).await?;
+        if let Some(raw_wav) = attempt.await? {
+            tracing::debug!("Generated eSpeak after {i} tries");
+            break raw_wav;
+        }
 
-            if std::str::from_utf8(&stderr)
-                .unwrap()
-                .contains("mbrowrap error: unable to get .wav header from mbrola")
-            {
-                i += 1;
-                continue;
-            }
-        };
-
-        tracing::debug!("Generated eSpeak after {i} tries");
-        break output.stdout;
+        crate::metrics::get().espeak_retries.inc();
+        i += 1;
     };
 
-    // Fix the wav header to set the ChunkSize and SubChunk2Size
-    // See:
-    // - https://github.com/hadware/voxpopuli/blob/fb94a6130c046bb9f7a27aaaed2a4b434666faa9/voxpopuli/main.py#L150-L158
-    // - http://soundfile.sapp.org/doc/WaveFormat/
-    let wav_len: u32 = raw_wav.len().try_into().expect("WAV data too long!");
-
-    raw_wav[4..8].copy_from_slice(&(wav_len - 8).to_le_bytes());
-    raw_wav[40..44].copy_from_slice(&(wav_len - 44).to_le_bytes());
+    // mbrola writes to a pipe, so cannot go back to fill in the RIFF and data chunk sizes.
+    // See: https://github.com/hadware/voxpopuli/blob/fb94a6130c046bb9f7a27aaaed2a4b434666faa9/voxpopuli/main.py#L150-L158
+    crate::wav::fix_sizes(&mut raw_wav)?;
 
     Ok((
         bytes::Bytes::from(raw_wav),
@@ -105,13 +56,84 @@ pub async fn get_tts(
     ))
 }
 
-pub fn check_length(audio: &[u8], max_length: u32) -> bool {
-    audio.len() as u32
-        / (u16::from_le_bytes(audio[22..24].try_into().unwrap()) as u32 * // Sample Rate
-        u32::from_le_bytes(audio[24..28].try_into().unwrap()) *        // Number of Channels
-        u16::from_le_bytes(audio[34..36].try_into().unwrap()) as u32   // Bits per Sample
-        / 8)
-        < max_length
+/// Runs espeak piped into mbrola, returning `None` if mbrola failed to write any samples.
+async fn generate_wav(text: &str, voice: &str, speaking_rate: u16) -> Result<Option<Vec<u8>>> {
+    let mut espeak_process = tokio::process::Command::new("espeak")
+        .kill_on_drop(true)
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .args([
+            "--pho",
+            "-q",
+            "-s",
+            &speaking_rate.to_string(),
+            "-v",
+            &format!("mb/mb-{voice}"),
+            text,
+        ])
+        .spawn()?;
+
+    let espeak_stdout: std::process::Stdio = espeak_process
+        .stdout
+        .take()
+        .expect("Failed to open espeak stdout")
+        .try_into()?;
+
+    let mut mbrola_process = tokio::process::Command::new("mbrola")
+        .kill_on_drop(true)
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .stdin(espeak_stdout)
+        .args([
+            "-e",
+            &format!("/usr/share/mbrola/{voice}/{voice}"),
+            "-",
+            "-.wav",
+        ])
+        .spawn()?;
+
+    // Filter out some warning messages from mbrola that clutter logs
+    if let Some(mut mbrola_stderr) = mbrola_process.stderr.take() {
+        tokio::spawn(async move {
+            let mut buffer = Vec::new();
+            while let Ok(written_bytes) = mbrola_stderr.read_buf(&mut buffer).await {
+                if written_bytes == 0 {
+                    break;
+                }
+
+                let current_msg =
+                    std::str::from_utf8(&buffer).unwrap_or("TTS Service Error: Invalid UTF8");
+                if !current_msg.contains("unknown, replaced with ") {
+                    tracing::error!("Mbrola Error: {current_msg}");
+                }
+
+                buffer.clear();
+            }
+
+            tracing::debug!("mbrola_stderr watcher closed");
+        });
+    }
+
+    let output = mbrola_process.wait_with_output().await?;
+    let has_samples = crate::wav::parse(&output.stdout).is_ok_and(|wav| !wav.data.is_empty());
+    if !has_samples {
+        let mut espeak_stderr = espeak_process
+            .stderr
+            .take()
+            .expect("Unable to open espeak stderr");
+
+        let mut stderr = Vec::new();
+        espeak_stderr.read_to_end(&mut stderr).await?;
+
+        if std::str::from_utf8(&stderr)
+            .unwrap()
+            .contains("mbrowrap error: unable to get .wav header from mbrola")
+        {
+            return Ok(None);
+        }
+    }
+
+    Ok(Some(output.stdout))
 }
 
 pub fn get_voices() -> &'static [String] {
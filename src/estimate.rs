@@ -0,0 +1,166 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use crate::{gtts, text::is_cjk, TTSMode};
+
+/// Seconds per character at normal speed, before calibration.
+const SECS_PER_CHAR: f64 = 0.07;
+/// CJK scripts pack a syllable or more into each character.
+const SECS_PER_CJK_CHAR: f64 = 0.22;
+
+/// How fast gTTS's slow mode speaks, relative to normal speed.
+const SLOW_GTTS_RATE: f32 = 0.7;
+
+/// Weight given to each new observation when calibrating.
+const CALIBRATION_WEIGHT: f64 = 0.1;
+/// Short text is dominated by leading and trailing silence, so is not used for calibration.
+const MIN_CALIBRATION_CHARS: usize = 20;
+
+/// Estimates how long audio will be before it is synthesized.
+///
+/// Each mode and voice has a correction factor, calibrated from the durations of previously generated audio.
+#[derive(Default)]
+pub struct Estimator {
+    corrections: Mutex<HashMap<(String, String), f64>>,
+}
+
+fn char_secs(c: char) -> f64 {
+    if is_cjk(c) {
+        SECS_PER_CJK_CHAR
+    } else if c.is_whitespace() || c.is_alphanumeric() {
+        SECS_PER_CHAR
+    } else {
+        // Punctuation is mostly silent, apart from the pause after it.
+        SECS_PER_CHAR / 2.0
+    }
+}
+
+/// How much slower than normal the mode will speak, in the units its `speaking_rate` uses.
+fn rate_factor(mode: TTSMode, speaking_rate: Option<f32>) -> f64 {
+    let (normal, rate) = match mode {
+        // Either normal or slow speed.
+        TTSMode::gTTS if gtts::is_slow(speaking_rate) => (1.0, SLOW_GTTS_RATE),
+        TTSMode::gTTS => return 1.0,
+        // Percent of normal speed.
+        TTSMode::Polly => (100.0, speaking_rate.unwrap_or(100.0)),
+        // Words per minute, where 0 falls back to the default.
+        TTSMode::eSpeak => (175.0, speaking_rate.filter(|r| *r > 0.0).unwrap_or(175.0)),
+        // Multiple of normal speed, where 0 falls back to the default.
+        TTSMode::gCloud => (1.0, speaking_rate.filter(|r| *r > 0.0).unwrap_or(1.0)),
+    };
+
+    if rate > 0.0 {
+        normal / f64::from(rate)
+    } else {
+        1.0
+    }
+}
+
+impl Estimator {
+    fn correction(&self, mode: TTSMode, voice: &str) -> f64 {
+        let corrections = self.corrections.lock().unwrap();
+        corrections
+            .get(&(mode.to_string(), voice.to_owned()))
+            .copied()
+            .unwrap_or(1.0)
+    }
+
+    /// The seconds each character of `text` is expected to take, in order.
+    fn char_estimates<'a>(
+        &self,
+        mode: TTSMode,
+        voice: &str,
+        text: &'a str,
+        speaking_rate: Option<f32>,
+    ) -> impl Iterator<Item = (usize, f64)> + 'a {
+        let scale = rate_factor(mode, speaking_rate) * self.correction(mode, voice);
+        text.char_indices()
+            .map(move |(i, c)| (i, char_secs(c) * scale))
+    }
+
+    pub fn estimate(
+        &self,
+        mode: TTSMode,
+        voice: &str,
+        text: &str,
+        speaking_rate: Option<f32>,
+    ) -> Duration {
+        let secs = self
+            .char_estimates(mode, voice, text, speaking_rate)
+            .map(|(_, secs)| secs)
+            .sum();
+
+        Duration::from_secs_f64(secs)
+    }
+
+    /// Cuts `text` down to what is expected to fit in `max_length`, at a word boundary if possible.
+    pub fn truncate<'a>(
+        &self,
+        mode: TTSMode,
+        voice: &str,
+        text: &'a str,
+        speaking_rate: Option<f32>,
+        max_length: Duration,
+    ) -> &'a str {
+        let max_secs = max_length.as_secs_f64();
+        let mut total = 0.0;
+        let end = self
+            .char_estimates(mode, voice, text, speaking_rate)
+            .find(|(_, secs)| {
+                total += secs;
+                total > max_secs
+            })
+            .map(|(i, _)| i);
+
+        let Some(end) = end else {
+            return text;
+        };
+
+        let text = &text[..end];
+        match text.rfind(char::is_whitespace) {
+            Some(word_end) if word_end > 0 => text[..word_end].trim_end(),
+            _ => text,
+        }
+    }
+
+    /// Calibrates the voice's correction factor from the real duration of generated audio.
+    pub fn observe(
+        &self,
+        mode: TTSMode,
+        voice: &str,
+        text: &str,
+        speaking_rate: Option<f32>,
+        duration: Duration,
+    ) {
+        if text.chars().count() < MIN_CALIBRATION_CHARS {
+            return;
+        }
+
+        let uncorrected = self
+            .estimate(mode, voice, text, speaking_rate)
+            .as_secs_f64()
+            / self.correction(mode, voice);
+
+        if uncorrected <= 0.0 {
+            return;
+        }
+
+        let observed = (duration.as_secs_f64() / uncorrected).clamp(0.25, 4.0);
+
+        let mut corrections = self.corrections.lock().unwrap();
+        let correction = corrections
+            .entry((mode.to_string(), voice.to_owned()))
+            .or_insert(observed);
+
+        *correction += (observed - *correction) * CALIBRATION_WEIGHT;
+    }
+}
+
+/// The estimator is kept outside of the state, so calibration survives config reloads.
+pub fn get() -> &'static Estimator {
+    static ESTIMATOR: OnceLock<Estimator> = OnceLock::new();
+    ESTIMATOR.get_or_init(Estimator::default)
+}
@@ -5,6 +5,119 @@ use crate::Result;
 
 const GOOGLE_API_BASE: &str = "https://texttospeech.googleapis.com/";
 
+/// List prices in USD per million characters, by voice family.
+const FAMILY_PRICES: [(&str, f64); 10] = [
+    ("Standard", 4.0),
+    ("Wavenet", 16.0),
+    ("Neural2", 16.0),
+    ("Polyglot", 16.0),
+    ("News", 16.0),
+    ("Casual", 16.0),
+    ("Journey", 30.0),
+    ("Chirp-HD", 30.0),
+    ("Chirp3-HD", 30.0),
+    ("Studio", 160.0),
+];
+
+/// The device classes Google can optimise audio for, sent as `effectsProfileId`.
+const EFFECTS_PROFILES: [&str; 8] = [
+    "wearable-class-device",
+    "handset-class-device",
+    "headphone-class-device",
+    "small-bluetooth-speaker-class-device",
+    "medium-bluetooth-speaker-class-device",
+    "large-home-entertainment-class-device",
+    "large-automotive-class-device",
+    "telephony-class-application",
+];
+
+const PITCH_RANGE: std::ops::RangeInclusive<f32> = -20.0..=20.0;
+const VOLUME_GAIN_RANGE: std::ops::RangeInclusive<f32> = -96.0..=16.0;
+const SAMPLE_RATE_RANGE: std::ops::RangeInclusive<u32> = 8000..=48000;
+const OPUS_SAMPLE_RATES: [u32; 5] = [8000, 12000, 16000, 24000, 48000];
+
+/// Optional `audioConfig` settings, on top of the encoding and speaking rate.
+#[derive(Default)]
+pub struct AudioConfig {
+    pitch: Option<f32>,
+    volume_gain_db: Option<f32>,
+    sample_rate_hertz: Option<u32>,
+    effects_profile_id: Vec<String>,
+}
+
+impl AudioConfig {
+    /// Checks each setting against the ranges Google accepts, with `effects_profile_id` comma separated.
+    pub fn new(
+        pitch: Option<f32>,
+        volume_gain_db: Option<f32>,
+        sample_rate_hertz: Option<u32>,
+        effects_profile_id: Option<&str>,
+        preferred_format: Option<&str>,
+    ) -> Result<Self> {
+        if let Some(pitch) = pitch.filter(|pitch| !PITCH_RANGE.contains(pitch)) {
+            anyhow::bail!("pitch must be between -20 and 20 semitones, not {pitch}");
+        }
+
+        if let Some(gain) = volume_gain_db.filter(|gain| !VOLUME_GAIN_RANGE.contains(gain)) {
+            anyhow::bail!("volume_gain_db must be between -96 and 16, not {gain}");
+        }
+
+        if let Some(rate) = sample_rate_hertz {
+            if !SAMPLE_RATE_RANGE.contains(&rate) {
+                anyhow::bail!("sample_rate_hertz must be between 8000 and 48000, not {rate}");
+            }
+
+            let encoding = AudioEncoding::from_preferred(preferred_format);
+            if matches!(encoding, AudioEncoding::OGG_OPUS) && !OPUS_SAMPLE_RATES.contains(&rate) {
+                anyhow::bail!("sample_rate_hertz of {rate} is not supported by OGG_OPUS");
+            }
+        }
+
+        let effects_profile_id = effects_profile_id
+            .into_iter()
+            .flat_map(|profiles| profiles.split(','))
+            .map(|profile| {
+                let profile = profile.trim();
+                if EFFECTS_PROFILES.contains(&profile) {
+                    Ok(profile.to_owned())
+                } else {
+                    anyhow::bail!(
+                        "effects_profile_id {profile:?} must be one of {}",
+                        EFFECTS_PROFILES.join(", ")
+                    )
+                }
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            pitch,
+            volume_gain_db,
+            sample_rate_hertz,
+            effects_profile_id,
+        })
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.pitch.is_none()
+            && self.volume_gain_db.is_none()
+            && self.sample_rate_hertz.is_none()
+            && self.effects_profile_id.is_empty()
+    }
+}
+
+impl std::fmt::Display for AudioConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "pitch {} gain {}dB rate {}Hz effects {}",
+            self.pitch.unwrap_or(0.0),
+            self.volume_gain_db.unwrap_or(0.0),
+            self.sample_rate_hertz.unwrap_or(0),
+            self.effects_profile_id.join(",")
+        )
+    }
+}
+
 #[derive(Clone)]
 pub struct State {
     service_account: ServiceAccount,
@@ -14,11 +127,10 @@ pub struct State {
 }
 
 impl State {
-    pub(crate) fn new(reqwest: reqwest::Client) -> Result<RwLock<Self>> {
-        let service_account: ServiceAccount = serde_json::from_str(&std::fs::read_to_string(
-            std::env::var("GOOGLE_APPLICATION_CREDENTIALS").unwrap(),
-        )?)?;
-
+    pub(crate) fn new(
+        reqwest: reqwest::Client,
+        service_account: ServiceAccount,
+    ) -> Result<RwLock<Self>> {
         let (jwt_token, expire_time) = generate_jwt(
             service_account.private_key.clone(),
             &service_account.client_email,
@@ -41,11 +153,21 @@ struct AudioResponse<'a> {
 }
 
 #[derive(Clone, serde::Deserialize)]
-struct ServiceAccount {
+pub struct ServiceAccount {
     pub private_key: String,
     pub client_email: String,
 }
 
+impl ServiceAccount {
+    /// Reads the service account JSON at `path`, checking the private key can be used.
+    pub fn load(path: &std::path::Path) -> Result<Self> {
+        let service_account: Self = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        jsonwebtoken::EncodingKey::from_rsa_pem(service_account.private_key.as_bytes())?;
+
+        Ok(service_account)
+    }
+}
+
 #[derive(serde::Deserialize, serde::Serialize, Default, Clone, Copy)]
 pub enum Gender {
     #[serde(rename = "MALE")]
@@ -64,6 +186,68 @@ pub struct GoogleVoice {
     #[serde(default)]
     pub ssmlGender: Gender,
     pub languageCodes: [String; 1],
+    /// Filled in from the name, such as `Wavenet` or `Chirp3-HD`.
+    #[serde(default, skip_deserializing)]
+    pub family: String,
+    #[serde(default, skip_deserializing)]
+    pub pricePerMillionChars: Option<f64>,
+}
+
+impl GoogleVoice {
+    /// Splits the name after the language code into the family and variant, such as `Wavenet` and `A`.
+    fn family_and_variant(&self) -> Option<(&str, &str)> {
+        let [language] = &self.languageCodes;
+        self.name
+            .strip_prefix(language.as_str())?
+            .strip_prefix('-')?
+            .rsplit_once('-')
+    }
+
+    /// The voice as accepted by the `lang` parameter, which is `{language} {variant}` for
+    /// Standard voices and `{language} {family}-{variant}` for every other family.
+    fn id(&self) -> Option<String> {
+        let [language] = &self.languageCodes;
+        Some(match self.family_and_variant()? {
+            ("Standard", variant) => format!("{language} {variant}"),
+            (family, variant) => format!("{language} {family}-{variant}"),
+        })
+    }
+}
+
+/// Splits a voice ID into the language code and the full Google voice name.
+fn parse_voice(voice: &str) -> Result<(&str, String)> {
+    let (lang, variant) = voice
+        .split_once(' ')
+        .ok_or_else(|| anyhow::anyhow!("{voice} cannot be parsed into lang and variant"))?;
+
+    // Standard voices leave out the family, as they did before other families were supported.
+    let name = if variant.contains('-') {
+        format!("{lang}-{variant}")
+    } else {
+        format!("{lang}-Standard-{variant}")
+    };
+
+    Ok((lang, name))
+}
+
+/// Whether `name` is a known voice family, matching the case Google uses.
+pub fn is_family(name: &str) -> bool {
+    FAMILY_PRICES.iter().any(|&(family, _)| family == name)
+}
+
+/// The known voice families, separated by commas.
+pub fn family_names() -> String {
+    FAMILY_PRICES.map(|(family, _)| family).join(", ")
+}
+
+/// The family of a voice ID, such as `Standard` or `Studio`.
+pub fn family(voice: &str) -> &str {
+    match voice.split_once(' ') {
+        Some((_, variant)) => variant
+            .rsplit_once('-')
+            .map_or("Standard", |(family, _)| family),
+        None => "Standard",
+    }
 }
 
 #[allow(non_camel_case_types, clippy::upper_case_acronyms)]
@@ -88,6 +272,12 @@ impl AudioEncoding {
         }
     }
 
+    fn from_preferred(preferred_format: Option<&str>) -> Self {
+        preferred_format
+            .and_then(|pf| Self::from_str(&pf.to_uppercase()))
+            .unwrap_or(Self::OGG_OPUS)
+    }
+
     fn as_str(self) -> &'static str {
         match self {
             AudioEncoding::LINEAR16 => "LINEAR16",
@@ -112,10 +302,27 @@ fn generate_google_json(
     lang: &str,
     speaking_rate: f32,
     audio_encoding: &str,
+    audio_config: &AudioConfig,
 ) -> Result<impl serde::Serialize> {
-    let (lang, variant) = lang
-        .split_once(' ')
-        .ok_or_else(|| anyhow::anyhow!("{lang} cannot be parsed into lang and variant"))?;
+    let (lang, name) = parse_voice(lang)?;
+
+    // Google's defaults are used for any setting that was not given.
+    let mut google_audio_config = serde_json::json!({
+        "audioEncoding": audio_encoding,
+        "speakingRate": speaking_rate
+    });
+    if let Some(pitch) = audio_config.pitch {
+        google_audio_config["pitch"] = pitch.into();
+    }
+    if let Some(volume_gain_db) = audio_config.volume_gain_db {
+        google_audio_config["volumeGainDb"] = volume_gain_db.into();
+    }
+    if let Some(sample_rate_hertz) = audio_config.sample_rate_hertz {
+        google_audio_config["sampleRateHertz"] = sample_rate_hertz.into();
+    }
+    if !audio_config.effects_profile_id.is_empty() {
+        google_audio_config["effectsProfileId"] = audio_config.effects_profile_id.clone().into();
+    }
 
     Ok(serde_json::json!({
         "input": {
@@ -123,12 +330,9 @@ fn generate_google_json(
         },
         "voice": {
             "languageCode": lang,
-            "name": format!("{lang}-Standard-{variant}"),
+            "name": name,
         },
-        "audioConfig": {
-            "audioEncoding": audio_encoding,
-            "speakingRate": speaking_rate
-        }
+        "audioConfig": google_audio_config
     }))
 }
 
@@ -175,7 +379,7 @@ async fn refresh_jwt(state: &RwLock<State>) -> Result<String> {
 
         let mut state = state.write().await;
 
-        state.jwt_token = jwt_token.clone();
+        state.jwt_token.clone_from(&jwt_token);
         state.expire_time = new_expire_time;
 
         Ok(jwt_token)
@@ -184,19 +388,35 @@ async fn refresh_jwt(state: &RwLock<State>) -> Result<String> {
     }
 }
 
+/// Checks the service account can still be used to generate a JWT.
+pub async fn check_ready(state: &RwLock<State>) -> Result<()> {
+    let service_account = state.read().await.service_account.clone();
+    generate_jwt(
+        service_account.private_key,
+        &service_account.client_email,
+        std::time::SystemTime::now(),
+    )?;
+
+    Ok(())
+}
+
+/// The content type of audio generated with `preferred_format`.
+pub fn content_type(preferred_format: Option<&str>) -> &'static str {
+    AudioEncoding::from_preferred(preferred_format).content_type()
+}
+
 pub async fn get_tts(
     state: &RwLock<State>,
     text: &str,
     lang: &str,
     speaking_rate: f32,
     preferred_format: Option<String>,
+    audio_config: &AudioConfig,
 ) -> Result<(bytes::Bytes, Option<reqwest::header::HeaderValue>)> {
     let jwt_token = refresh_jwt(state).await?;
     let reqwest = state.read().await.reqwest.clone();
 
-    let audio_encoding = preferred_format
-        .and_then(|pf| AudioEncoding::from_str(&pf.to_uppercase()))
-        .unwrap_or(AudioEncoding::OGG_OPUS);
+    let audio_encoding = AudioEncoding::from_preferred(preferred_format.as_deref());
 
     let resp = reqwest
         .post(format!("{GOOGLE_API_BASE}v1/text:synthesize"))
@@ -205,6 +425,7 @@ pub async fn get_tts(
             lang,
             speaking_rate,
             audio_encoding.as_str(),
+            audio_config,
         )?)
         .header(
             reqwest::header::AUTHORIZATION,
@@ -228,7 +449,7 @@ pub async fn get_tts(
 }
 
 static VOICES: tokio::sync::OnceCell<Vec<GoogleVoice>> = tokio::sync::OnceCell::const_new();
-async fn _get_voices(state: &RwLock<State>) -> Result<Vec<GoogleVoice>> {
+async fn fetch_voices(state: &RwLock<State>) -> Result<Vec<GoogleVoice>> {
     #[derive(serde::Deserialize)]
     struct VoiceResponse {
         voices: Vec<GoogleVoice>,
@@ -246,7 +467,19 @@ async fn _get_voices(state: &RwLock<State>) -> Result<Vec<GoogleVoice>> {
         .json()
         .await?;
 
-    Ok(resp.voices)
+    let mut voices = resp.voices;
+    for voice in &mut voices {
+        if let Some((family, _)) = voice.family_and_variant() {
+            voice.family = family.to_owned();
+        }
+
+        voice.pricePerMillionChars = FAMILY_PRICES
+            .iter()
+            .find(|(family, _)| *family == voice.family)
+            .map(|(_, price)| *price);
+    }
+
+    Ok(voices)
 }
 
 pub async fn check_voice(state: &RwLock<State>, voice: &str) -> Result<bool> {
@@ -254,27 +487,124 @@ pub async fn check_voice(state: &RwLock<State>, voice: &str) -> Result<bool> {
 }
 
 pub async fn get_raw_voices(state: &RwLock<State>) -> Result<&'static Vec<GoogleVoice>> {
-    VOICES.get_or_try_init(|| _get_voices(state)).await
+    VOICES.get_or_try_init(|| fetch_voices(state)).await
 }
 
 pub async fn get_voices(state: &RwLock<State>) -> Result<Vec<String>> {
     Ok(VOICES
-        .get_or_try_init(|| _get_voices(state))
+        .get_or_try_init(|| fetch_voices(state))
         .await?
         .iter()
-        .filter_map(|gvoice| {
-            gvoice
-                .name
-                .splitn(3, '-')
-                .nth(2)?
-                .split_once('-')
-                .filter(|(mode, _)| *mode == "Standard")
-                .map(|(_, variant)| {
-                    let [mut language] = gvoice.languageCodes.clone();
-                    language.push(' ');
-                    language.push_str(variant);
-                    language
-                })
-        })
+        .filter_map(GoogleVoice::id)
         .collect())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{generate_google_json, AudioConfig};
+
+    fn rejects(config: crate::Result<AudioConfig>, message: &str) {
+        match config {
+            Ok(config) => panic!("{config} was accepted, expected {message:?}"),
+            Err(err) => assert_eq!(err.to_string(), message),
+        }
+    }
+
+    #[test]
+    fn accepts_the_edges_of_each_range() {
+        let config = AudioConfig::new(Some(-20.0), Some(16.0), Some(48000), None, None).unwrap();
+        assert_eq!(
+            config.to_string(),
+            "pitch -20 gain 16dB rate 48000Hz effects "
+        );
+
+        let config = AudioConfig::new(Some(20.0), Some(-96.0), Some(8000), None, None).unwrap();
+        assert!(!config.is_default());
+        assert!(AudioConfig::new(None, None, None, None, None)
+            .unwrap()
+            .is_default());
+    }
+
+    #[test]
+    fn rejects_values_outside_the_ranges() {
+        rejects(
+            AudioConfig::new(Some(20.5), None, None, None, None),
+            "pitch must be between -20 and 20 semitones, not 20.5",
+        );
+        rejects(
+            AudioConfig::new(Some(f32::NAN), None, None, None, None),
+            "pitch must be between -20 and 20 semitones, not NaN",
+        );
+        rejects(
+            AudioConfig::new(None, Some(-97.0), None, None, None),
+            "volume_gain_db must be between -96 and 16, not -97",
+        );
+        rejects(
+            AudioConfig::new(None, None, Some(7999), None, None),
+            "sample_rate_hertz must be between 8000 and 48000, not 7999",
+        );
+        rejects(
+            AudioConfig::new(None, None, Some(96000), None, Some("mp3")),
+            "sample_rate_hertz must be between 8000 and 48000, not 96000",
+        );
+    }
+
+    #[test]
+    fn only_checks_opus_sample_rates_for_opus() {
+        rejects(
+            AudioConfig::new(None, None, Some(22050), None, None),
+            "sample_rate_hertz of 22050 is not supported by OGG_OPUS",
+        );
+        rejects(
+            AudioConfig::new(None, None, Some(44100), None, Some("ogg_opus")),
+            "sample_rate_hertz of 44100 is not supported by OGG_OPUS",
+        );
+
+        assert!(AudioConfig::new(None, None, Some(24000), None, None).is_ok());
+        assert!(AudioConfig::new(None, None, Some(22050), None, Some("mp3")).is_ok());
+        assert!(AudioConfig::new(None, None, Some(44100), None, Some("linear16")).is_ok());
+    }
+
+    #[test]
+    fn parses_comma_separated_effects_profiles() {
+        let profiles = "handset-class-device, telephony-class-application";
+        let config = AudioConfig::new(None, None, None, Some(profiles), None).unwrap();
+        assert_eq!(
+            config.effects_profile_id,
+            ["handset-class-device", "telephony-class-application"]
+        );
+
+        let config = AudioConfig::new(None, None, None, Some("handset-class-device,phone"), None);
+        let err = config.err().unwrap().to_string();
+        assert!(
+            err.starts_with("effects_profile_id \"phone\" must be one of "),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn only_sends_the_settings_that_were_given() {
+        let json = |audio_config: &AudioConfig| {
+            let body = generate_google_json("Hello", "en-US A", 1.0, "OGG_OPUS", audio_config);
+            serde_json::to_value(body.unwrap()).unwrap()["audioConfig"].take()
+        };
+
+        assert_eq!(
+            json(&AudioConfig::default()),
+            serde_json::json!({"audioEncoding": "OGG_OPUS", "speakingRate": 1.0})
+        );
+
+        let profiles = Some("headphone-class-device");
+        let config = AudioConfig::new(Some(-2.5), None, Some(16000), profiles, None).unwrap();
+        assert_eq!(
+            json(&config),
+            serde_json::json!({
+                "audioEncoding": "OGG_OPUS",
+                "speakingRate": 1.0,
+                "pitch": -2.5,
+                "sampleRateHertz": 16000,
+                "effectsProfileId": ["headphone-class-device"]
+            })
+        );
+    }
+}
@@ -1,79 +1,470 @@
-use std::sync::OnceLock;
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error as _,
+    net::{IpAddr, Ipv4Addr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock, Weak,
+    },
+    time::Duration,
+};
 
-use itertools::Itertools;
-use rand::Rng;
-use tokio::sync::RwLock;
+use futures_util::{StreamExt, TryStreamExt};
+use ipnetwork::IpNetwork;
+use tokio::{sync::Notify, time::Instant};
+use tracing::Instrument;
 
-use crate::Result;
+use crate::{
+    config::{GttsConfig, IpSelection},
+    text::is_cjk,
+    Result,
+};
 
-#[derive(Clone)]
+/// The `ttsspeed` Google Translate uses for its own slow playback.
+const SLOW_SPEED: &str = "0.24";
+
+/// Regional accents as `(language, TLD, region)`, served by the Google domain for that region.
+///
+/// Each is available as a voice of `{language} {TLD}`, such as `en com.au`.
+const ACCENTS: [(&str, &str, &str); 14] = [
+    ("en", "com.au", "Australia"),
+    ("en", "co.uk", "United Kingdom"),
+    ("en", "us", "United States"),
+    ("en", "ca", "Canada"),
+    ("en", "co.in", "India"),
+    ("en", "ie", "Ireland"),
+    ("en", "co.za", "South Africa"),
+    ("en", "com.ng", "Nigeria"),
+    ("fr", "ca", "Canada"),
+    ("fr", "fr", "France"),
+    ("pt", "com.br", "Brazil"),
+    ("pt", "pt", "Portugal"),
+    ("es", "com.mx", "Mexico"),
+    ("es", "es", "Spain"),
+];
+
+/// The most text Google will synthesize in one request, in UTF-16 code units as its web client counts them.
+const MAX_CHUNK_LEN: usize = 200;
+
+/// How long the replenisher sleeps between checks, if it is not woken by a block.
+const REPLENISH_INTERVAL: Duration = Duration::from_secs(10);
+/// How long the replenisher backs off after failing to check a new address.
+const REPLENISH_BACKOFF: Duration = Duration::from_secs(5);
+/// How many random addresses to try when looking for one that has not recently failed.
+const MAX_PICK_ATTEMPTS: usize = 16;
+
+/// A source address, along with its health.
+struct Address {
+    ip: IpAddr,
+    http: reqwest::Client,
+    added: Instant,
+    blocked_until: Option<Instant>,
+    last_blocked: Option<Instant>,
+    blocks: u64,
+    requests: u64,
+}
+
+impl Address {
+    fn new(ip: IpAddr, http: reqwest::Client) -> Self {
+        Self {
+            ip,
+            http,
+            added: Instant::now(),
+            blocked_until: None,
+            last_blocked: None,
+            blocks: 0,
+            requests: 0,
+        }
+    }
+
+    fn is_available(&self, now: Instant) -> bool {
+        self.blocked_until.map_or(true, |until| until <= now)
+    }
+}
+
+/// A pool of source addresses from the IPv6 block, rested for a cooldown after being blocked.
+///
+/// Without an IPv6 block, the pool holds only the default address, which is never rested.
 pub struct State {
-    ip: std::net::IpAddr,
-    pub http: reqwest::Client,
+    base_url: reqwest::Url,
+    ip_block: Option<IpNetwork>,
+    timeout: Duration,
+    pool_size: usize,
+    cooldown: Duration,
+    selection: IpSelection,
+    addresses: Mutex<Vec<Address>>,
+    /// Addresses that failed a check or were blocked, and when they can next be picked.
+    failed: Mutex<HashMap<IpAddr, Instant>>,
+    next: AtomicUsize,
+    /// Wakes requests waiting for an address to become available.
+    available: Notify,
+    /// Wakes the replenisher when an address is blocked.
+    replenish: Notify,
 }
 
-fn get_base_url() -> reqwest::Url {
-    static BASE_URL: OnceLock<reqwest::Url> = OnceLock::new();
-    BASE_URL
-        .get_or_init(|| {
-            reqwest::Url::parse(
-                "https://translate.google.com/translate_tts?ie=UTF-8&total=1&idx=0&client=tw-ob",
-            )
-            .unwrap()
-        })
-        .clone()
+#[derive(serde::Serialize)]
+pub struct PoolStatus {
+    size: usize,
+    available: usize,
+    selection: IpSelection,
+    addresses: Vec<AddressStatus>,
 }
 
-fn parse_url(text: &str, lang: &str) -> reqwest::Url {
-    let mut url = get_base_url();
-    url.query_pairs_mut()
-        .append_pair("tl", lang)
-        .append_pair("q", text)
-        .append_pair("textlen", &text.len().to_string())
-        .finish();
-    url
+/// Durations are in seconds.
+#[derive(serde::Serialize)]
+struct AddressStatus {
+    ip: IpAddr,
+    available: bool,
+    cooldown_remaining: f64,
+    last_blocked: Option<f64>,
+    blocks: u64,
+    requests: u64,
+    age: f64,
 }
 
-pub async fn get_random_ipv6() -> Result<State> {
-    let ip_block = match std::env::var("IPV6_BLOCK") {
-        Ok(ip_block) if &ip_block == "DISABLE" => {
-            return Ok(State {
-                ip: "0.0.0.0".parse()?,
-                http: reqwest::Client::new(),
-            })
+impl State {
+    /// Creates the pool once a first address has been checked, then fills the rest in the background.
+    pub async fn new(config: &GttsConfig, timeout: Duration) -> Result<Arc<Self>> {
+        Self::with_base_url(config, timeout, get_base_url()).await
+    }
+
+    async fn with_base_url(
+        config: &GttsConfig,
+        timeout: Duration,
+        base_url: reqwest::Url,
+    ) -> Result<Arc<Self>> {
+        let ip_block = config.ipv6_block.as_deref().map(str::parse).transpose()?;
+        let state = Arc::new(Self {
+            base_url,
+            ip_block,
+            timeout,
+            pool_size: if ip_block.is_some() {
+                config.ip_pool_size
+            } else {
+                1
+            },
+            cooldown: Duration::from_secs_f64(config.ip_cooldown),
+            selection: config.ip_selection,
+            addresses: Mutex::new(Vec::new()),
+            failed: Mutex::new(HashMap::new()),
+            next: AtomicUsize::new(0),
+            available: Notify::new(),
+            replenish: Notify::new(),
+        });
+
+        let Some(ip_block) = ip_block else {
+            let http = reqwest::Client::builder().timeout(timeout).build()?;
+            state.add(Address::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), http));
+            return Ok(state);
+        };
+
+        let first = loop {
+            if let Some(address) = state.new_address(ip_block).await? {
+                break address;
+            }
+        };
+
+        state.add(first);
+        tokio::spawn(replenish(Arc::downgrade(&state)));
+        Ok(state)
+    }
+
+    /// The index of the address that would be picked next, without advancing the round-robin.
+    fn peek(&self, addresses: &[Address], now: Instant) -> Option<usize> {
+        match self.selection {
+            IpSelection::RoundRobin => {
+                let start = self.next.load(Ordering::Relaxed);
+                let len = addresses.len();
+                (0..len)
+                    .map(|offset| (start + offset) % len)
+                    .find(|&index| addresses[index].is_available(now))
+            }
+            IpSelection::LeastRecentlyBlocked => addresses
+                .iter()
+                .enumerate()
+                .filter(|(_, address)| address.is_available(now))
+                .min_by_key(|(_, address)| (address.last_blocked, address.requests))
+                .map(|(index, _)| index),
         }
-        Ok(ip_block) => ip_block.parse().expect("Invalid IPV6 Block!"),
-        _ => panic!("IPV6_BLOCK not set! Set to \"DISABLE\" to disable rate limit bypass"),
-    };
+    }
 
-    let mut attempts = 1;
-    loop {
-        let name: String = rand::thread_rng()
-            .sample_iter::<char, _>(rand::distributions::Standard)
-            .take(16)
+    fn select<'a>(&self, addresses: &'a mut [Address], now: Instant) -> Option<&'a mut Address> {
+        let index = self.peek(addresses, now)?;
+
+        // Carries on after the picked address, so a cooling address's turn is not
+        // given to its neighbour every time. The pool lock is held, so this cannot race.
+        self.next.store(index + 1, Ordering::Relaxed);
+        Some(&mut addresses[index])
+    }
+
+    /// Waits for an available address, returning it along with a client bound to it.
+    async fn acquire(&self) -> (IpAddr, reqwest::Client) {
+        loop {
+            // Registered before checking, so an address added in between is not missed.
+            let notified = self.available.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            let next_available = {
+                let now = Instant::now();
+                let mut addresses = self.addresses.lock().unwrap();
+                if let Some(address) = self.select(&mut addresses, now) {
+                    address.requests += 1;
+                    return (address.ip, address.http.clone());
+                }
+
+                addresses
+                    .iter()
+                    .filter_map(|address| address.blocked_until)
+                    .min()
+            };
+
+            self.replenish.notify_one();
+            match next_available {
+                Some(until) => tokio::select! {
+                    () = notified => {},
+                    () = tokio::time::sleep_until(until) => {},
+                },
+                None => notified.await,
+            }
+        }
+    }
+
+    /// Rests a blocked address for the cooldown, and wakes the replenisher to replace it.
+    fn block(&self, ip: IpAddr) {
+        let now = Instant::now();
+        let mut addresses = self.addresses.lock().unwrap();
+        let Some(address) = addresses.iter_mut().find(|address| address.ip == ip) else {
+            return;
+        };
+
+        // Requests in flight on the same address fail together, but only count as one block.
+        if address.is_available(now) {
+            tracing::warn!("IP {ip} has been blocked!");
+            crate::metrics::get().gtts_ip_rotations.inc();
+
+            address.blocked_until = Some(now + self.cooldown);
+            address.last_blocked = Some(now);
+            address.blocks += 1;
+            self.replenish.notify_one();
+        }
+
+        drop(addresses);
+        self.record_failure(ip);
+    }
+
+    /// Stops `ip` being picked for a new address until the cooldown has passed.
+    fn record_failure(&self, ip: IpAddr) {
+        let until = Instant::now() + self.cooldown;
+        self.failed.lock().unwrap().insert(ip, until);
+    }
+
+    /// Picks a random address from the block that is not in the pool and has not recently failed.
+    fn pick_address(&self, ip_block: IpNetwork) -> Option<IpAddr> {
+        let in_pool: HashSet<_> = self
+            .addresses
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|address| address.ip)
             .collect();
 
-        tracing::debug!("Generated random name: {:?}", name.as_bytes());
-        let ip = ipgen::ip(&name, ip_block).unwrap();
+        let now = Instant::now();
+        let mut failed = self.failed.lock().unwrap();
+        failed.retain(|_, until| *until > now);
+
+        std::iter::repeat_with(|| random_address(ip_block))
+            .take(MAX_PICK_ATTEMPTS)
+            .find(|ip| !in_pool.contains(ip) && !failed.contains_key(ip))
+    }
+
+    /// Picks a new address from the block, returning it if a test request is not blocked.
+    async fn new_address(&self, ip_block: IpNetwork) -> Result<Option<Address>> {
+        let Some(ip) = self.pick_address(ip_block) else {
+            anyhow::bail!("Every address picked from {ip_block} is in use or recently failed");
+        };
 
         let http = reqwest::Client::builder()
-            .connect_timeout(std::time::Duration::from_secs(5))
+            .connect_timeout(Duration::from_secs(5))
+            .timeout(self.timeout)
             .local_address(Some(ip))
             .build()?;
 
-        let check_request = http.get(parse_url("Hello", "en")).send().await;
+        let check_request = http.get(self.url("Hello", "en", false)).send().await;
         let fail_reason = match is_block(check_request).await? {
             CheckResult::Ok(..) => {
                 tracing::warn!("Generated random IP: {ip}");
-                break Ok(State { ip, http });
+                return Ok(Some(Address::new(ip, http)));
             }
             CheckResult::NormalBlock => "429 block",
             CheckResult::TimeoutBlock => "timeout block",
             CheckResult::HostUnreachable => "unreachable error",
         };
 
-        tracing::warn!("Failed to generate a new IP on attempt {attempts} with a {fail_reason}");
-        attempts += 1;
+        tracing::warn!("Failed to add new IP {ip} with a {fail_reason}");
+        self.record_failure(ip);
+        Ok(None)
+    }
+
+    /// Drops addresses back from their cooldown if they have already been replaced,
+    /// returning how many more addresses are needed.
+    fn prune(&self) -> usize {
+        let now = Instant::now();
+        let mut addresses = self.addresses.lock().unwrap();
+        let mut available = addresses.iter().filter(|a| a.is_available(now)).count();
+
+        while available > self.pool_size {
+            let Some((index, _)) = addresses
+                .iter()
+                .enumerate()
+                .filter(|(_, address)| address.is_available(now) && address.last_blocked.is_some())
+                .max_by_key(|(_, address)| address.last_blocked)
+            else {
+                break;
+            };
+
+            addresses.swap_remove(index);
+            available -= 1;
+        }
+
+        self.pool_size.saturating_sub(available)
+    }
+
+    fn add(&self, address: Address) {
+        self.addresses.lock().unwrap().push(address);
+        self.available.notify_waiters();
+    }
+
+    pub fn status(&self) -> PoolStatus {
+        let now = Instant::now();
+        let addresses = self.addresses.lock().unwrap();
+        let addresses: Vec<_> = addresses
+            .iter()
+            .map(|address| AddressStatus {
+                ip: address.ip,
+                available: address.is_available(now),
+                cooldown_remaining: address.blocked_until.map_or(0.0, |until| {
+                    until.saturating_duration_since(now).as_secs_f64()
+                }),
+                last_blocked: address
+                    .last_blocked
+                    .map(|last_blocked| now.duration_since(last_blocked).as_secs_f64()),
+                blocks: address.blocks,
+                requests: address.requests,
+                age: now.duration_since(address.added).as_secs_f64(),
+            })
+            .collect();
+
+        PoolStatus {
+            size: self.pool_size,
+            available: addresses.iter().filter(|a| a.available).count(),
+            selection: self.selection,
+            addresses,
+        }
+    }
+
+    fn url(&self, text: &str, voice: &str, slow: bool) -> reqwest::Url {
+        parse_url(&self.base_url, text, voice, slow)
+    }
+
+    /// Checks if the settings the pool was created with are unchanged, so it can be kept on reload.
+    pub fn matches(&self, config: &GttsConfig, timeout: Duration) -> bool {
+        let ip_block = config.ipv6_block.as_deref().and_then(|b| b.parse().ok());
+        self.ip_block == ip_block
+            && self.timeout == timeout
+            && (self.ip_block.is_none() || self.pool_size == config.ip_pool_size)
+            && self.cooldown == Duration::from_secs_f64(config.ip_cooldown)
+            && self.selection == config.ip_selection
+    }
+}
+
+/// Keeps the pool topped up with checked addresses, until the pool is dropped.
+async fn replenish(state: Weak<State>) {
+    loop {
+        let Some(state) = state.upgrade() else {
+            return;
+        };
+
+        let missing = state.prune();
+        if missing == 0 {
+            // Holding the pool while waiting is fine, as the wait is bounded.
+            let _ = tokio::time::timeout(REPLENISH_INTERVAL, state.replenish.notified()).await;
+            continue;
+        }
+
+        let Some(ip_block) = state.ip_block else {
+            return;
+        };
+
+        match state.new_address(ip_block).await {
+            Ok(Some(address)) => state.add(address),
+            Ok(None) => {}
+            Err(err) => {
+                tracing::error!("Failed to check new gTTS address: {err:#}");
+                tokio::time::sleep(REPLENISH_BACKOFF).await;
+            }
+        }
+    }
+}
+
+fn get_base_url() -> reqwest::Url {
+    static BASE_URL: OnceLock<reqwest::Url> = OnceLock::new();
+    BASE_URL
+        .get_or_init(|| {
+            reqwest::Url::parse(
+                "https://translate.google.com/translate_tts?ie=UTF-8&total=1&idx=0&client=tw-ob",
+            )
+            .unwrap()
+        })
+        .clone()
+}
+
+/// Builds the request URL from `base_url` for `voice`, which is a language optionally followed by the TLD of an accent.
+fn parse_url(base_url: &reqwest::Url, text: &str, voice: &str, slow: bool) -> reqwest::Url {
+    let mut url = base_url.clone();
+    let lang = match voice.split_once(' ') {
+        Some((lang, tld)) => {
+            url.set_host(Some(&format!("translate.google.{tld}")))
+                .expect("accent TLDs should be valid hosts");
+            lang
+        }
+        None => voice,
+    };
+
+    let mut query = url.query_pairs_mut();
+    query
+        .append_pair("tl", lang)
+        .append_pair("q", text)
+        .append_pair("textlen", &utf16_len(text).to_string());
+
+    if slow {
+        query.append_pair("ttsspeed", SLOW_SPEED);
+    }
+
+    query.finish();
+    drop(query);
+    url
+}
+
+/// gTTS only has a normal and a slow speed, so any `speaking_rate` below 1 selects slow,
+/// and anything else is normal speed.
+pub fn is_slow(speaking_rate: Option<f32>) -> bool {
+    speaking_rate.is_some_and(|rate| rate > 0.0 && rate < 1.0)
+}
+
+/// A random address in the block, keeping the network bits and randomising the rest.
+fn random_address(ip_block: IpNetwork) -> IpAddr {
+    match ip_block {
+        IpNetwork::V6(network) => {
+            let host_mask = u128::MAX.checked_shr(network.prefix().into()).unwrap_or(0);
+            let host = rand::random::<u128>() & host_mask;
+            IpAddr::V6((u128::from(network.network()) | host).into())
+        }
+        IpNetwork::V4(network) => {
+            let host_mask = u32::MAX.checked_shr(network.prefix().into()).unwrap_or(0);
+            let host = rand::random::<u32>() & host_mask;
+            IpAddr::V4((u32::from(network.network()) | host).into())
+        }
     }
 }
 
@@ -84,11 +475,19 @@ enum CheckResult {
     HostUnreachable,
 }
 
+/// Checks if the request failed because the source address cannot reach Google, such as when
+/// it is not routed or not assigned to this host.
 fn is_host_unreachable(err: &reqwest::Error) -> bool {
-    let debug_message = format!("{err:?}");
-    ["No route to host", "HostUnreachable"]
-        .into_iter()
-        .all(|s| debug_message.contains(s))
+    std::iter::successors(err.source(), |err| (*err).source())
+        .filter_map(|err| err.downcast_ref::<std::io::Error>())
+        .any(|err| {
+            matches!(
+                err.kind(),
+                std::io::ErrorKind::HostUnreachable
+                    | std::io::ErrorKind::NetworkUnreachable
+                    | std::io::ErrorKind::AddrNotAvailable
+            )
+        })
 }
 
 async fn is_block(resp: reqwest::Result<reqwest::Response>) -> Result<CheckResult> {
@@ -115,44 +514,152 @@ async fn is_block(resp: reqwest::Result<reqwest::Response>) -> Result<CheckResul
     }
 }
 
+/// Checks an available address is not blocked, without waiting for one or changing the pool.
+pub async fn check_ready(state: &Arc<State>) -> Result<()> {
+    let address = {
+        let addresses = state.addresses.lock().unwrap();
+        let len = addresses.len();
+        state
+            .peek(&addresses, Instant::now())
+            .map(|index| (addresses[index].ip, addresses[index].http.clone()))
+            .ok_or_else(|| anyhow::anyhow!("All {len} IPs are cooling down after being blocked"))?
+    };
+
+    let (ip, http) = address;
+    let result = is_block(http.get(state.url("Hello", "en", false)).send().await).await?;
+    let fail_reason = match result {
+        CheckResult::Ok(..) => return Ok(()),
+        CheckResult::NormalBlock => "has a 429 block",
+        CheckResult::TimeoutBlock => "has a timeout block",
+        CheckResult::HostUnreachable => "is unreachable",
+    };
+
+    anyhow::bail!("IP {ip} {fail_reason}")
+}
+
+fn utf16_len(text: &str) -> usize {
+    text.chars().map(char::len_utf16).sum()
+}
+
+/// How good a place splitting text after `c` is, from a sentence end down to between CJK characters.
+fn break_priority(c: char, next: Option<char>) -> Option<u8> {
+    // ASCII punctuation is also used inside numbers and abbreviations, so needs a space after it.
+    let space_after = next.map_or(true, char::is_whitespace);
+    match c {
+        '。' | '！' | '？' => Some(4),
+        '.' | '!' | '?' | '…' if space_after => Some(4),
+        '，' | '、' | '；' | '：' => Some(3),
+        ',' | ';' | ':' | '—' if space_after => Some(3),
+        _ if c.is_whitespace() => Some(2),
+        // CJK text has no spaces, so can be split between any two characters.
+        _ if is_cjk(c) || next.is_some_and(is_cjk) => Some(1),
+        _ => None,
+    }
+}
+
+/// Splits text into chunks of at most [`MAX_CHUNK_LEN`], preferring to split between sentences,
+/// then clauses, then words, and only cutting through a word if there is no other choice.
+fn split_text(text: &str) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    let mut rest = text.trim();
+    while utf16_len(rest) > MAX_CHUNK_LEN {
+        let mut len = 0;
+        let mut best: Option<(u8, usize)> = None;
+        let mut cut = 0;
+
+        let mut chars = rest.char_indices().peekable();
+        while let Some((index, c)) = chars.next() {
+            len += c.len_utf16();
+            if len > MAX_CHUNK_LEN {
+                break;
+            }
+
+            cut = index + c.len_utf8();
+            let next = chars.peek().map(|(_, next)| *next);
+            if let Some(priority) = break_priority(c, next) {
+                // Later breaks of the same priority are preferred, for fuller chunks.
+                if best.map_or(true, |(best, _)| priority >= best) {
+                    best = Some((priority, cut));
+                }
+            }
+        }
+
+        let (chunk, next) = rest.split_at(best.map_or(cut, |(_, split)| split));
+        chunks.push(chunk.trim_end());
+        rest = next.trim_start();
+    }
+
+    if !rest.is_empty() {
+        chunks.push(rest);
+    }
+
+    chunks
+}
+
+/// Fetches one chunk of text, moving to another address and retrying whenever one is blocked.
+///
+/// Without an IPv6 block, failures are returned instead, as there is no other address to move to.
+async fn get_chunk(
+    state: &State,
+    index: usize,
+    chunk: &str,
+    voice: &str,
+    slow: bool,
+) -> Result<(Option<reqwest::header::HeaderValue>, bytes::Bytes)> {
+    let mut attempt = 1;
+    loop {
+        let (ip, http) = state.acquire().await;
+        let request = http.get(state.url(chunk, voice, slow)).send();
+        let span = tracing::info_span!("gtts_request", chunk = index, attempt, %ip);
+        let result = request.instrument(span).await;
+
+        let fail_reason = match is_block(result).await? {
+            CheckResult::Ok(content_type, audio) => return Ok((content_type, audio)),
+            CheckResult::NormalBlock => "429 block",
+            CheckResult::TimeoutBlock => "timeout block",
+            CheckResult::HostUnreachable => "unreachable error",
+        };
+
+        // Without a block there is no other address to move to, so resting the only one would
+        // stall every request for the cooldown over a single rate limit or network blip.
+        if state.ip_block.is_none() {
+            anyhow::bail!("gTTS request from {ip} failed with a {fail_reason}");
+        }
+
+        attempt += 1;
+        state.block(ip);
+    }
+}
+
+/// Fetches up to `chunk_concurrency` chunks of the text at once, joining them back together in order.
 pub async fn get_tts(
-    state: &RwLock<State>,
+    state: &State,
     text: &str,
     voice: &str,
+    speaking_rate: Option<f32>,
+    chunk_concurrency: usize,
 ) -> Result<(bytes::Bytes, Option<reqwest::header::HeaderValue>)> {
-    let mut content_type = None;
-    let mut audio = Vec::new();
-
-    let chunks: Vec<String> = text
-        .chars()
-        .chunks(200)
+    // The futures are created up front, as a closure in the stream would not be `Send`.
+    let requests: Vec<_> = split_text(text)
         .into_iter()
-        .map(Iterator::collect)
+        .enumerate()
+        .map(|(index, chunk)| get_chunk(state, index, chunk, voice, is_slow(speaking_rate)))
         .collect();
-    for chunk in chunks {
-        loop {
-            let (ip, result) = {
-                let State { ip, http } = state.read().await.clone();
-                (ip, http.get(parse_url(&chunk, voice)).send().await)
-            };
 
-            if let CheckResult::Ok(content_type_, audio_chunk) = is_block(result).await? {
-                if let Some(content_type_) = content_type_ {
-                    content_type = Some(content_type_);
-                }
+    let results: Vec<_> = futures_util::stream::iter(requests)
+        .buffered(chunk_concurrency)
+        .try_collect()
+        .await?;
 
-                break audio.extend(audio_chunk);
-            }
-
-            // Generate a new client, with an new IP, and try again
-            let mut state = state.write().await;
-            if state.ip == ip {
-                tracing::warn!("IP {ip} has been blocked!");
-                *state = get_random_ipv6().await?;
-            }
-        }
+    let mut content_type = None;
+    let mut audio_chunks = Vec::with_capacity(results.len());
+    for (chunk_content_type, audio_chunk) in results {
+        content_type = chunk_content_type.or(content_type);
+        audio_chunks.push(audio_chunk);
     }
 
+    // Each chunk is a complete MP3 file, with its own tags and headers.
+    let audio = crate::mp3::join(&audio_chunks)?;
     Ok((bytes::Bytes::from(audio), content_type))
 }
 
@@ -165,5 +672,446 @@ pub fn get_voices() -> Vec<String> {
 }
 
 pub fn get_raw_voices() -> std::collections::BTreeMap<String, String> {
-    serde_json::from_str(include_str!("data/voices-gtts.json")).unwrap()
+    let mut voices: std::collections::BTreeMap<String, String> =
+        serde_json::from_str(include_str!("data/voices-gtts.json")).unwrap();
+
+    for (lang, tld, region) in ACCENTS {
+        let name = format!("{} ({region})", voices[lang]);
+        voices.insert(format!("{lang} {tld}"), name);
+    }
+
+    voices
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use axum::{http::StatusCode, response::IntoResponse};
+
+    use super::{Address, CheckResult, State};
+    use crate::config::{GttsConfig, IpSelection};
+
+    const TIMEOUT: Duration = Duration::from_millis(500);
+
+    #[derive(Clone, Copy)]
+    enum Failure {
+        RateLimit,
+        Hang,
+    }
+
+    struct Mock {
+        failure: Failure,
+        failures: usize,
+        requests: AtomicUsize,
+    }
+
+    /// A silent MPEG-2 Layer III frame at 24 kHz and 32 kbps, the format Google returns.
+    fn mp3_frame() -> Vec<u8> {
+        let mut frame = vec![0; 96];
+        frame[..4].copy_from_slice(&[0xFF, 0xF3, 0x44, 0xC4]);
+        frame
+    }
+
+    async fn translate_tts(
+        axum::extract::State(mock): axum::extract::State<Arc<Mock>>,
+    ) -> axum::response::Response {
+        if mock.requests.fetch_add(1, Ordering::SeqCst) < mock.failures {
+            match mock.failure {
+                Failure::RateLimit => return StatusCode::TOO_MANY_REQUESTS.into_response(),
+                Failure::Hang => tokio::time::sleep(TIMEOUT * 4).await,
+            }
+        }
+
+        ([("Content-Type", "audio/mpeg")], mp3_frame()).into_response()
+    }
+
+    /// Serves a mock of Google's TTS endpoint, which fails the first `failures` requests.
+    async fn mock_server(failure: Failure, failures: usize) -> (reqwest::Url, Arc<Mock>) {
+        let mock = Arc::new(Mock {
+            failure,
+            failures,
+            requests: AtomicUsize::new(0),
+        });
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = axum::Router::new()
+            .route("/translate_tts", axum::routing::get(translate_tts))
+            .with_state(mock.clone());
+
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let url = format!("http://{addr}/translate_tts?client=tw-ob");
+        (reqwest::Url::parse(&url).unwrap(), mock)
+    }
+
+    fn config(ipv6_block: Option<&str>) -> GttsConfig {
+        GttsConfig {
+            ipv6_block: ipv6_block.map(String::from),
+            ip_cooldown: 0.1,
+            ..GttsConfig::default()
+        }
+    }
+
+    /// A pool of two addresses which both reach `url`, so blocking one moves to the other.
+    fn two_addresses(url: reqwest::Url) -> State {
+        let mut state = pool(IpSelection::RoundRobin, 2, Duration::from_secs(60), 2);
+        state.base_url = url;
+        state
+    }
+
+    #[tokio::test]
+    async fn retries_after_rate_limit() {
+        let (url, mock) = mock_server(Failure::RateLimit, 1).await;
+        let state = two_addresses(url);
+
+        let (audio, _) = super::get_tts(&state, "Hello", "en", None, 1)
+            .await
+            .unwrap();
+
+        assert_eq!(crate::mp3::sample_rate(&audio), Some(24000));
+        assert_eq!(mock.requests.load(Ordering::SeqCst), 2);
+        assert_eq!(state.status().addresses[0].blocks, 1);
+    }
+
+    #[tokio::test]
+    async fn retries_after_timeout() {
+        let (url, mock) = mock_server(Failure::Hang, 1).await;
+        let state = two_addresses(url);
+
+        super::get_tts(&state, "Hello", "en", None, 1)
+            .await
+            .unwrap();
+
+        assert_eq!(mock.requests.load(Ordering::SeqCst), 2);
+        assert_eq!(state.status().addresses[0].blocks, 1);
+    }
+
+    #[tokio::test]
+    async fn returns_failures_without_a_block() {
+        for failure in [Failure::RateLimit, Failure::Hang] {
+            let (url, mock) = mock_server(failure, 1).await;
+            let mut state = State::with_base_url(&config(None), TIMEOUT, url)
+                .await
+                .unwrap();
+            Arc::get_mut(&mut state).unwrap().cooldown = Duration::from_secs(60);
+
+            let err = super::get_tts(&state, "Hello", "en", None, 1)
+                .await
+                .err()
+                .unwrap();
+            assert!(err.to_string().contains("block"), "{err}");
+            assert_eq!(mock.requests.load(Ordering::SeqCst), 1);
+
+            // The only address was not rested, so the next request is not held up.
+            let next = tokio::time::timeout(TIMEOUT, super::get_tts(&state, "Hi", "en", None, 1));
+            next.await.unwrap().unwrap();
+            assert_eq!(state.status().addresses[0].blocks, 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn joins_every_chunk() {
+        let (url, mock) = mock_server(Failure::RateLimit, 0).await;
+        let state = State::with_base_url(&config(None), TIMEOUT, url)
+            .await
+            .unwrap();
+
+        let text = "Hello there. ".repeat(50);
+        let (audio, _) = super::get_tts(&state, &text, "en", None, 2).await.unwrap();
+
+        let chunks = mock.requests.load(Ordering::SeqCst);
+        assert_eq!(chunks, super::split_text(&text).len());
+        assert_eq!(
+            mp3_duration::from_read(&mut &audio[..]).unwrap(),
+            Duration::from_millis(24) * u32::try_from(chunks).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn detects_unreachable_address() {
+        let (url, mock) = mock_server(Failure::RateLimit, 0).await;
+
+        // A documentation address, which is not assigned to this host so cannot be bound to.
+        let http = reqwest::Client::builder()
+            .local_address("192.0.2.1".parse().ok())
+            .build()
+            .unwrap();
+
+        let result = super::is_block(http.get(url).send().await).await.unwrap();
+        assert!(matches!(result, CheckResult::HostUnreachable));
+        assert_eq!(mock.requests.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_failed_addresses() {
+        let (url, _) = mock_server(Failure::RateLimit, 0).await;
+
+        // Neither address in the block is assigned to this host, so both fail, then there are none left to try.
+        let result = tokio::time::timeout(
+            Duration::from_secs(5),
+            State::with_base_url(&config(Some("192.0.2.0/31")), TIMEOUT, url),
+        )
+        .await
+        .expect("failed addresses should not be retried");
+
+        let err = result.err().unwrap();
+        assert!(err.to_string().contains("recently failed"), "{err}");
+    }
+
+    /// Splits `text`, checking every chunk is within the limit and nothing but whitespace is lost.
+    fn split(text: &str) -> Vec<&str> {
+        let chunks = super::split_text(text);
+        for chunk in &chunks {
+            assert!(super::utf16_len(chunk) <= super::MAX_CHUNK_LEN, "{chunk:?}");
+        }
+
+        let strip = |text: &str| text.split_whitespace().collect::<String>();
+        assert_eq!(strip(&chunks.concat()), strip(text));
+        chunks
+    }
+
+    #[test]
+    fn splits_at_200_utf16_code_units() {
+        assert_eq!(split(&"a".repeat(200)).len(), 1);
+        assert_eq!(split(&"a".repeat(201)).len(), 2);
+
+        // Emoji are two code units each, so only 100 fit in a chunk.
+        let emoji = "😀".repeat(150);
+        assert_eq!(split(&emoji), ["😀".repeat(100), "😀".repeat(50)]);
+    }
+
+    #[test]
+    fn prefers_sentence_then_clause_then_word_breaks() {
+        let sentences = "This is a sentence. ".repeat(15);
+        assert!(split(&sentences).iter().all(|chunk| chunk.ends_with('.')));
+
+        let clause = "word ".repeat(30);
+        let clauses = format!("{}, and {}", clause.trim_end(), "word ".repeat(20));
+        assert_eq!(split(&clauses)[0], format!("{},", clause.trim_end()));
+
+        let words = "word ".repeat(60);
+        assert!(split(&words).iter().all(|chunk| chunk.ends_with("word")));
+    }
+
+    #[test]
+    fn does_not_split_decimals_or_abbreviations() {
+        for text in ["pi is 3.14159 ".repeat(20), "see e.g. this ".repeat(20)] {
+            let words: Vec<_> = text.split_whitespace().collect();
+            for chunk in split(&text) {
+                assert!(chunk.split_whitespace().all(|word| words.contains(&word)));
+            }
+        }
+    }
+
+    #[test]
+    fn splits_cjk_text_without_spaces() {
+        let sentences = "这是一个测试句子。".repeat(30);
+        assert!(split(&sentences).iter().all(|chunk| chunk.ends_with('。')));
+
+        let unpunctuated = "测试".repeat(150);
+        assert_eq!(
+            split(&unpunctuated),
+            ["测试".repeat(100), "测试".repeat(50)]
+        );
+    }
+
+    #[test]
+    fn cuts_long_words_only_when_there_is_no_other_break() {
+        let word = "x".repeat(450);
+        assert_eq!(
+            split(&word),
+            ["x".repeat(200), "x".repeat(200), "x".repeat(50)]
+        );
+
+        let text = format!("hello {word}");
+        assert_eq!(split(&text)[0], "hello");
+    }
+
+    /// A pool of `count` addresses from the documentation block, without checking or replenishing them.
+    fn pool(selection: IpSelection, pool_size: usize, cooldown: Duration, count: u16) -> State {
+        let addresses = (1..=count)
+            .map(|i| {
+                let ip = std::net::Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, i);
+                let http = reqwest::Client::builder().timeout(TIMEOUT).build().unwrap();
+                Address::new(ip.into(), http)
+            })
+            .collect();
+
+        State {
+            base_url: super::get_base_url(),
+            ip_block: Some("2001:db8::/64".parse().unwrap()),
+            timeout: TIMEOUT,
+            pool_size,
+            cooldown,
+            selection,
+            addresses: std::sync::Mutex::new(addresses),
+            failed: std::sync::Mutex::default(),
+            next: AtomicUsize::new(0),
+            available: tokio::sync::Notify::new(),
+            replenish: tokio::sync::Notify::new(),
+        }
+    }
+
+    fn ips(state: &State) -> Vec<std::net::IpAddr> {
+        let addresses = state.addresses.lock().unwrap();
+        addresses.iter().map(|address| address.ip).collect()
+    }
+
+    #[tokio::test]
+    async fn round_robin_skips_cooling_addresses() {
+        let state = pool(IpSelection::RoundRobin, 3, Duration::from_secs(60), 3);
+        let ips = ips(&state);
+        state.block(ips[1]);
+
+        let mut picked = Vec::new();
+        for _ in 0..6 {
+            picked.push(state.acquire().await.0);
+        }
+
+        assert!(!picked.contains(&ips[1]));
+        assert_eq!(picked.iter().filter(|ip| **ip == ips[0]).count(), 3);
+        assert_eq!(picked.iter().filter(|ip| **ip == ips[2]).count(), 3);
+    }
+
+    #[tokio::test]
+    async fn least_recently_blocked_picks_the_longest_rested() {
+        let state = pool(IpSelection::LeastRecentlyBlocked, 3, Duration::ZERO, 3);
+        let ips = ips(&state);
+
+        let now = tokio::time::Instant::now();
+        {
+            let mut addresses = state.addresses.lock().unwrap();
+            addresses[0].last_blocked = Some(now - Duration::from_secs(30));
+            addresses[2].last_blocked = Some(now - Duration::from_secs(60));
+        }
+
+        // Never blocked comes first, then the oldest block.
+        assert_eq!(state.acquire().await.0, ips[1]);
+        state.addresses.lock().unwrap()[1].blocked_until = Some(now + Duration::from_secs(60));
+        assert_eq!(state.acquire().await.0, ips[2]);
+
+        // Ties are broken by the fewest requests.
+        state.addresses.lock().unwrap()[0].last_blocked = Some(now - Duration::from_secs(60));
+        assert_eq!(state.acquire().await.0, ips[0]);
+    }
+
+    #[test]
+    fn prune_drops_recovered_extras() {
+        let state = pool(IpSelection::RoundRobin, 2, Duration::ZERO, 4);
+        let original = ips(&state);
+
+        let now = tokio::time::Instant::now();
+        {
+            let mut addresses = state.addresses.lock().unwrap();
+            // Recovered from cooldowns, so were replaced while resting.
+            addresses[0].last_blocked = Some(now - Duration::from_secs(20));
+            addresses[1].last_blocked = Some(now - Duration::from_secs(10));
+            // Still cooling down, so is kept until it recovers.
+            addresses[3].last_blocked = Some(now);
+            addresses[3].blocked_until = Some(now + Duration::from_secs(60));
+        }
+
+        // The most recently blocked of the recovered addresses goes first.
+        assert_eq!(state.prune(), 0);
+        assert_eq!(ips(&state).len(), 3);
+        assert!(!ips(&state).contains(&original[1]));
+
+        state
+            .addresses
+            .lock()
+            .unwrap()
+            .retain(|address| address.ip != original[2]);
+        assert_eq!(state.prune(), 1);
+    }
+
+    #[tokio::test]
+    async fn acquire_waits_for_cooldown_to_expire() {
+        let cooldown = Duration::from_millis(200);
+        let state = pool(IpSelection::RoundRobin, 1, cooldown, 1);
+        let ip = ips(&state)[0];
+        state.block(ip);
+
+        let start = tokio::time::Instant::now();
+        let (acquired, _) = tokio::time::timeout(cooldown * 5, state.acquire())
+            .await
+            .unwrap();
+
+        assert_eq!(acquired, ip);
+        assert!(start.elapsed() >= cooldown / 2);
+    }
+
+    #[tokio::test]
+    async fn acquire_wakes_when_an_address_is_added() {
+        let state = Arc::new(pool(IpSelection::RoundRobin, 1, Duration::from_secs(60), 1));
+        state.block(ips(&state)[0]);
+
+        let waiter = tokio::spawn({
+            let state = state.clone();
+            async move { state.acquire().await.0 }
+        });
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let ip = std::net::Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0xff).into();
+        state.add(Address::new(ip, reqwest::Client::new()));
+
+        let acquired = tokio::time::timeout(TIMEOUT, waiter).await.unwrap();
+        assert_eq!(acquired.unwrap(), ip);
+    }
+
+    /// A pool of only the default address, which cannot reach `url` as it is bound to an unassigned address.
+    async fn unreachable_default_address() -> (Arc<State>, Arc<Mock>) {
+        let (url, mock) = mock_server(Failure::RateLimit, 0).await;
+        let mut state = pool(IpSelection::RoundRobin, 1, Duration::from_secs(60), 0);
+        state.ip_block = None;
+        state.base_url = url;
+
+        let http = reqwest::Client::builder()
+            .local_address("192.0.2.1".parse().ok())
+            .build()
+            .unwrap();
+        state.add(Address::new(std::net::Ipv4Addr::UNSPECIFIED.into(), http));
+
+        (Arc::new(state), mock)
+    }
+
+    #[tokio::test]
+    async fn does_not_block_the_default_address_when_unreachable() {
+        let (state, mock) = unreachable_default_address().await;
+
+        let result = tokio::time::timeout(TIMEOUT, super::get_tts(&state, "Hello", "en", None, 1));
+        assert!(result.await.unwrap().is_err());
+        assert_eq!(mock.requests.load(Ordering::SeqCst), 0);
+        assert_eq!(state.status().addresses[0].blocks, 0);
+    }
+
+    #[tokio::test]
+    async fn check_ready_does_not_change_the_pool() {
+        let (state, _) = unreachable_default_address().await;
+
+        assert!(super::check_ready(&state).await.is_err());
+        assert_eq!(state.status().available, 1);
+        assert!(state.failed.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn check_ready_does_not_advance_round_robin() {
+        let (url, _) = mock_server(Failure::RateLimit, 0).await;
+        let mut state = pool(IpSelection::RoundRobin, 3, Duration::from_secs(60), 3);
+        state.base_url = url;
+        let state = Arc::new(state);
+
+        for _ in 0..2 {
+            super::check_ready(&state).await.unwrap();
+        }
+
+        assert_eq!(state.acquire().await.0, ips(&state)[0]);
+    }
 }
@@ -0,0 +1,123 @@
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use axum::{http::StatusCode, response::IntoResponse};
+
+use crate::{audio, espeak, gcloud, gtts, polly, RedisCache, Result, TTSMode};
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long the result of a check that sends requests to a provider is reused for.
+const REMOTE_CHECK_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, serde::Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+enum Status {
+    Ok,
+    Disabled,
+    Error { error: String },
+}
+
+/// Runs the check if the dependency is enabled, giving up after [`CHECK_TIMEOUT`].
+async fn check(future: Option<impl Future<Output = Result<()>>>) -> Status {
+    let Some(future) = future else {
+        return Status::Disabled;
+    };
+
+    match tokio::time::timeout(CHECK_TIMEOUT, future).await {
+        Ok(Ok(())) => Status::Ok,
+        Ok(Err(err)) => Status::Error {
+            error: format!("{err:#}"),
+        },
+        Err(_) => Status::Error {
+            error: format!("Check timed out after {CHECK_TIMEOUT:?}"),
+        },
+    }
+}
+
+/// The last result of a check that uses provider quota, shared between concurrent probes.
+#[derive(Default)]
+struct CachedCheck(tokio::sync::Mutex<Option<(Instant, Status)>>);
+
+impl CachedCheck {
+    /// Runs the check if it is enabled and the last result is older than [`REMOTE_CHECK_TTL`].
+    async fn check(&self, future: Option<impl Future<Output = Result<()>>>) -> Status {
+        if future.is_none() {
+            return Status::Disabled;
+        }
+
+        let mut cached = self.0.lock().await;
+        if let Some((checked_at, status)) = &*cached {
+            if checked_at.elapsed() < REMOTE_CHECK_TTL {
+                return status.clone();
+            }
+        }
+
+        let status = check(future).await;
+        *cached = Some((Instant::now(), status.clone()));
+        status
+    }
+}
+
+/// The cached results of the remote checks, kept in the reloadable state so a reload checks the new settings.
+#[derive(Default)]
+pub struct RemoteChecks {
+    gtts: CachedCheck,
+    polly: CachedCheck,
+}
+
+async fn check_redis(redis: Option<&RedisCache>) -> Status {
+    check(redis.map(|redis| async move {
+        let mut conn = redis.client.get().await?;
+        deadpool_redis::redis::cmd("PING")
+            .query_async::<_, String>(&mut conn)
+            .await?;
+
+        Ok(())
+    }))
+    .await
+}
+
+/// Liveness probe, only checks the process is able to respond.
+pub async fn health() -> &'static str {
+    "OK"
+}
+
+/// Readiness probe, checks each dependency and returns their status as JSON.
+pub async fn ready() -> impl IntoResponse {
+    let state = crate::get_state();
+    let espeak_enabled = state.config.espeak.mode.enabled;
+    let checks = &state.remote_checks;
+    let (redis, ffmpeg, gtts, polly, espeak, gcloud) = tokio::join!(
+        check_redis(state.redis.as_ref()),
+        check(Some(async { audio::check_ready() })),
+        checks
+            .gtts
+            .check(state.gtts.as_ref().map(gtts::check_ready)),
+        checks
+            .polly
+            .check(state.polly.as_ref().map(polly::check_ready)),
+        check(espeak_enabled.then_some(async { espeak::check_ready() })),
+        check(state.gcloud.as_ref().map(gcloud::check_ready)),
+    );
+
+    let statuses = [&redis, &ffmpeg, &gtts, &polly, &espeak, &gcloud];
+    let status = if statuses.iter().any(|s| matches!(s, Status::Error { .. })) {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    };
+
+    let body = serde_json::json!({
+        "redis": redis,
+        "ffmpeg": ffmpeg,
+        TTSMode::gTTS.to_string(): gtts,
+        TTSMode::Polly.to_string(): polly,
+        TTSMode::eSpeak.to_string(): espeak,
+        TTSMode::gCloud.to_string(): gcloud,
+    });
+
+    (status, axum::Json(body))
+}
@@ -0,0 +1,155 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use crate::{config::Config, Error, ResponseResult, Result, TTSMode};
+
+/// Caps the number of concurrent syntheses for a mode, with a bounded queue of waiters.
+pub struct Limiter {
+    permits: Semaphore,
+    queued: AtomicUsize,
+    max_queue: usize,
+    max_queue_time: Duration,
+}
+
+struct QueueGuard<'a>(&'a AtomicUsize);
+
+impl Drop for QueueGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl Limiter {
+    fn new(permits: usize, max_queue: usize, max_queue_time: Duration) -> Self {
+        Self {
+            permits: Semaphore::new(permits),
+            queued: AtomicUsize::new(0),
+            max_queue,
+            max_queue_time,
+        }
+    }
+
+    pub async fn acquire(&self) -> ResponseResult<SemaphorePermit<'_>> {
+        if let Ok(permit) = self.permits.try_acquire() {
+            return Ok(permit);
+        }
+
+        if self.queued.fetch_add(1, Ordering::AcqRel) >= self.max_queue {
+            self.queued.fetch_sub(1, Ordering::AcqRel);
+            return Err(Error::Overloaded);
+        }
+
+        let _guard = QueueGuard(&self.queued);
+        match tokio::time::timeout(self.max_queue_time, self.permits.acquire()).await {
+            Ok(permit) => Ok(permit?),
+            Err(_) => Err(Error::Overloaded),
+        }
+    }
+}
+
+/// The settings the limiters are built from, to tell if a reload changed them.
+#[derive(PartialEq)]
+struct Settings {
+    max_queue: usize,
+    max_queue_time: f64,
+    gtts: Option<usize>,
+    polly: Option<usize>,
+    espeak: Option<usize>,
+    gcloud: Option<usize>,
+    gcloud_families: BTreeMap<String, usize>,
+}
+
+impl Settings {
+    fn new(config: &Config) -> Self {
+        Self {
+            max_queue: config.limits.queue_size,
+            max_queue_time: config.limits.queue_timeout,
+            gtts: config.gtts.max_concurrency,
+            polly: config.polly.max_concurrency,
+            espeak: config.espeak.workers,
+            gcloud: config.gcloud.max_concurrency,
+            gcloud_families: config.gcloud.family_max_concurrency.0.clone(),
+        }
+    }
+}
+
+pub struct Limits {
+    settings: Settings,
+    gtts: Option<Limiter>,
+    polly: Option<Limiter>,
+    espeak: Option<Limiter>,
+    gcloud: Option<Limiter>,
+    gcloud_families: HashMap<String, Limiter>,
+}
+
+impl Limits {
+    pub fn new(config: &Config) -> Result<Self> {
+        let max_queue = config.limits.queue_size;
+        let max_queue_time = Duration::from_secs_f64(config.limits.queue_timeout);
+        let limiter = |permits: Option<usize>| {
+            permits.map(|permits| Limiter::new(permits, max_queue, max_queue_time))
+        };
+
+        // eSpeak forks two processes per request, so is always limited to avoid starving the host.
+        let espeak_workers = match config.espeak.workers {
+            Some(workers) => workers,
+            None => std::thread::available_parallelism()?.get(),
+        };
+
+        Ok(Self {
+            settings: Settings::new(config),
+            gtts: limiter(config.gtts.max_concurrency),
+            polly: limiter(config.polly.max_concurrency),
+            gcloud: limiter(config.gcloud.max_concurrency),
+            espeak: limiter(Some(espeak_workers)),
+            gcloud_families: config
+                .gcloud
+                .family_max_concurrency
+                .0
+                .iter()
+                .map(|(family, &permits)| {
+                    let limiter = Limiter::new(permits, max_queue, max_queue_time);
+                    (family.clone(), limiter)
+                })
+                .collect(),
+        })
+    }
+
+    /// Checks if the limits are unchanged, so the limiters can be kept on reload.
+    ///
+    /// In-flight requests hold permits from the current limiters, so replacing them would let
+    /// new requests exceed the limits until those finish.
+    pub fn matches(&self, config: &Config) -> bool {
+        self.settings == Settings::new(config)
+    }
+
+    pub async fn acquire(&self, mode: TTSMode) -> ResponseResult<Option<SemaphorePermit<'_>>> {
+        let limiter = match mode {
+            TTSMode::gTTS => &self.gtts,
+            TTSMode::Polly => &self.polly,
+            TTSMode::eSpeak => &self.espeak,
+            TTSMode::gCloud => &self.gcloud,
+        };
+
+        match limiter {
+            Some(limiter) => limiter.acquire().await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Waits for the gCloud voice `family`'s own limit, if it has one.
+    pub async fn acquire_gcloud_family(
+        &self,
+        family: &str,
+    ) -> ResponseResult<Option<SemaphorePermit<'_>>> {
+        match self.gcloud_families.get(family) {
+            Some(limiter) => limiter.acquire().await.map(Some),
+            None => Ok(None),
+        }
+    }
+}
@@ -8,31 +8,37 @@
 
 use std::{
     fmt::{Display, Write as _},
-    str::FromStr,
-    sync::OnceLock,
+    sync::{Arc, OnceLock},
+    time::Duration,
 };
 
+use arc_swap::ArcSwap;
 use axum::{http::header::HeaderValue, response::Response};
 use bytes::Bytes;
 use deadpool_redis::redis::AsyncCommands;
 use serde_json::to_value;
 use sha2::Digest;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use tracing::Instrument;
 
+mod audio;
+mod config;
 mod espeak;
+mod estimate;
 mod gcloud;
 mod gtts;
+mod health;
+mod limits;
+mod metrics;
+mod mp3;
 mod polly;
+mod server;
+mod telemetry;
+mod text;
+mod wav;
 
 type Result<T, E = anyhow::Error> = std::result::Result<T, E>;
 type ResponseResult<T> = std::result::Result<T, Error>;
 
-#[must_use]
-pub fn check_mp3_length(audio: &[u8], max_length: u64) -> bool {
-    use bytes::Buf;
-    mp3_duration::from_read(&mut audio.reader()).map_or(true, |d| d.as_secs() < max_length)
-}
-
 #[derive(serde::Deserialize)]
 struct GetVoices {
     mode: TTSMode,
@@ -44,25 +50,37 @@ async fn get_voices(
     axum::extract::Query(payload): axum::extract::Query<GetVoices>,
 ) -> ResponseResult<impl axum::response::IntoResponse> {
     let GetVoices { mode, raw } = payload;
-    let state = STATE.get().unwrap();
+    let state = get_state();
+    mode.check_enabled(&state)?;
 
     Ok(axum::Json(if raw {
         match mode {
             TTSMode::gTTS => to_value(gtts::get_raw_voices()),
             TTSMode::eSpeak => to_value(espeak::get_voices()),
-            TTSMode::Polly => to_value(polly::get_raw_voices(&state.polly).await?),
-            TTSMode::gCloud => to_value(gcloud::get_raw_voices(&state.gcloud).await?),
+            TTSMode::Polly => to_value(polly::get_raw_voices(state.polly()).await?),
+            TTSMode::gCloud => to_value(gcloud::get_raw_voices(state.gcloud()).await?),
         }?
     } else {
         to_value(match mode {
             TTSMode::gTTS => gtts::get_voices(),
             TTSMode::eSpeak => espeak::get_voices().to_vec(),
-            TTSMode::Polly => polly::get_voices(&state.polly).await?,
-            TTSMode::gCloud => gcloud::get_voices(&state.gcloud).await?,
+            TTSMode::Polly => polly::get_voices(state.polly()).await?,
+            TTSMode::gCloud => gcloud::get_voices(state.gcloud()).await?,
         })?
     }))
 }
 
+/// Lists the health of each address in the gTTS pool.
+async fn get_gtts_pool(
+    headers: axum::http::HeaderMap,
+) -> ResponseResult<axum::Json<gtts::PoolStatus>> {
+    let state = get_state();
+    check_auth(&state, &headers)?;
+    TTSMode::gTTS.check_enabled(&state)?;
+
+    Ok(axum::Json(state.gtts().status()))
+}
+
 #[derive(serde::Deserialize)]
 struct GetTTS {
     text: String,
@@ -74,43 +92,143 @@ struct GetTTS {
     max_length: Option<u64>,
     #[serde(default)]
     preferred_format: Option<String>,
+    #[serde(default)]
+    format: Option<audio::Format>,
+    sample_rate: Option<u32>,
+    channels: Option<u16>,
+    /// Cut the audio down to `max_length` instead of returning [`Error::AudioTooLong`].
+    #[serde(default)]
+    truncate: bool,
+    /// Milliseconds to fade out over when truncating.
+    fade_out: Option<u32>,
+    /// Target loudness in LUFS, or `off`, overriding the mode's configured target.
+    loudness: Option<String>,
+    /// gCloud only, semitones to shift the pitch by.
+    pitch: Option<f32>,
+    /// gCloud only, decibels to change the volume by.
+    volume_gain_db: Option<f32>,
+    /// gCloud only, the sample rate for Google to synthesize at.
+    sample_rate_hertz: Option<u32>,
+    /// gCloud only, comma separated device classes to optimise the audio for.
+    effects_profile_id: Option<String>,
 }
 
 async fn get_tts(
     axum::extract::Query(payload): axum::extract::Query<GetTTS>,
     headers: axum::http::HeaderMap,
 ) -> ResponseResult<Response<axum::body::Body>> {
-    let state = STATE.get().unwrap();
-    if let Some(auth_key) = state.auth_key.as_deref() {
-        if headers
-            .get("Authorization")
-            .map(HeaderValue::to_str)
-            .transpose()?
-            != Some(auth_key)
-        {
-            return Err(Error::Unauthorized);
-        }
+    let mode = payload.mode.to_string();
+    let result = generate_tts(payload, headers).await;
+
+    let metrics = metrics::get();
+    if let Err(err) = &result {
+        metrics.requests.with_label_values(&[&mode, "error"]).inc();
+        metrics.errors.with_label_values(&[&mode, err.kind()]).inc();
+    } else {
+        metrics.requests.with_label_values(&[&mode, "ok"]).inc();
     }
 
-    let preferred_format = payload.preferred_format;
-    let speaking_rate = payload.speaking_rate;
-    let mut voice = payload.voice;
+    result
+}
+
+#[tracing::instrument(
+    name = "tts",
+    skip_all,
+    fields(mode = %payload.mode, voice = payload.voice, text_length = payload.text.len())
+)]
+async fn generate_tts(
+    mut payload: GetTTS,
+    headers: axum::http::HeaderMap,
+) -> ResponseResult<Response<axum::body::Body>> {
+    let state = get_state();
+    check_auth(&state, &headers)?;
+
     let mode = payload.mode;
-    let text = payload.text;
+    let mut timeout = state.config.timeout(mode);
+    if let Some(deadline) = headers.get("X-Deadline") {
+        timeout = timeout.min(parse_deadline(deadline)?);
+    }
+
+    // Started on arrival, so time spent queueing for permits counts against the deadline.
+    let deadline = Deadline::new(timeout);
 
-    mode.check_speaking_rate(speaking_rate)?;
-    voice = mode.check_voice(state, voice).await?;
+    mode.check_enabled(&state)?;
+    mode.check_speaking_rate(payload.speaking_rate)?;
+    payload.voice = mode.check_voice(&state, payload.voice).await?;
+
+    let output = audio::Output::new(
+        payload.format,
+        payload.sample_rate,
+        payload.channels,
+        payload.preferred_format.as_deref(),
+    )?;
+
+    if payload.fade_out.is_some() && !payload.truncate {
+        return Err(Error::InvalidFormat(String::from(
+            "fade_out requires truncate to be set",
+        )));
+    }
+
+    let loudness = loudness_target(&state, &payload)?;
 
     let mut cache_key = format!(
-        "{text} | {voice} | {mode} | {}",
-        speaking_rate.unwrap_or(0.0)
+        "{} | {} | {mode} | {}",
+        payload.text,
+        payload.voice,
+        payload.speaking_rate.unwrap_or(0.0)
     );
 
-    if let Some(preferred_format) = preferred_format.as_ref() {
+    if let Some(preferred_format) = payload.preferred_format.as_ref() {
         write!(cache_key, "| {preferred_format}").unwrap();
     }
 
-    tracing::debug!("Recieved request to TTS: {cache_key}");
+    if let Some(output) = output {
+        write!(cache_key, "| {output}").unwrap();
+        payload.preferred_format = mode.native_format(output.format).map(String::from);
+    }
+
+    let audio_config = gcloud_audio_config(&payload)?;
+    if !audio_config.is_default() {
+        write!(cache_key, "| {audio_config}").unwrap();
+    }
+
+    let mut filters = Vec::new();
+    if state.config.trim_silence(mode) {
+        let silence = &state.config.silence;
+        write!(
+            cache_key,
+            "| trim {}dB {}s",
+            silence.threshold, silence.padding
+        )
+        .unwrap();
+        filters.push(audio::trim_silence_filter(
+            silence.threshold,
+            silence.padding,
+        ));
+    }
+
+    // Normalized after trimming, so silence does not drag down the measured loudness.
+    if let Some(loudness) = loudness {
+        write!(cache_key, "| {loudness} LUFS").unwrap();
+        filters.push(audio::loudness_filter(loudness));
+    }
+
+    let content_type = output.map_or_else(
+        || {
+            mode.content_type(payload.preferred_format.as_deref())
+                .to_owned()
+        },
+        |output| output.content_type(),
+    );
+
+    // The cache key starts with the text, so the rest is safe to log as-is.
+    let log_key = format!(
+        "{}{}",
+        telemetry::describe_text(&payload.text, state.config.log.redact_text),
+        &cache_key[payload.text.len()..]
+    );
+
+    tracing::debug!("Recieved request to TTS: {log_key}");
 
     let redis_info = if let Some(redis_state) = &state.redis {
         let cache_hash = {
@@ -119,19 +237,41 @@ async fn get_tts(
             hasher.finalize()
         };
 
-        let mut conn = redis_state.client.get().await?;
-        let cached_audio = conn
-            .get::<_, Option<String>>(&*cache_hash)
-            .await?
-            .map(|enc| redis_state.key.decrypt(&enc))
-            .transpose()?
-            .map(Bytes::from);
+        let (conn, cached_audio) = async {
+            let mut conn = redis_state
+                .client
+                .get()
+                .await
+                .map_err(metrics::redis_error)?;
+            let cached_audio = conn
+                .get::<_, Option<String>>(&*cache_hash)
+                .await
+                .map_err(metrics::redis_error)?
+                .map(|enc| {
+                    tracing::info_span!("fernet_decrypt").in_scope(|| redis_state.key.decrypt(&enc))
+                })
+                .transpose()?
+                .map(Bytes::from);
 
-        if let Some(cached_audio) = cached_audio {
-            mode.check_length(&cached_audio, payload.max_length)?;
+            ResponseResult::Ok((conn, cached_audio))
+        }
+        .instrument(tracing::info_span!("cache_lookup"))
+        .await?;
+
+        let cache_result = if cached_audio.is_some() {
+            "hit"
+        } else {
+            "miss"
+        };
 
-            tracing::debug!("Used cached TTS for {cache_key}");
-            return mode.into_response(cached_audio, None);
+        metrics::get()
+            .cache_lookups
+            .with_label_values(&[&mode.to_string(), cache_result])
+            .inc();
+
+        if let Some(cached_audio) = cached_audio {
+            tracing::debug!("Used cached TTS for {log_key}");
+            return respond(&state, cached_audio, &content_type, &payload, deadline).await;
         }
 
         Some((conn, &redis_state.key, cache_hash))
@@ -139,47 +279,341 @@ async fn get_tts(
         None
     };
 
-    let (audio, content_type) = match mode {
-        TTSMode::gTTS => gtts::get_tts(&state.gtts, &text, &voice).await?,
-        TTSMode::eSpeak => {
-            espeak::get_tts(&text, &voice, speaking_rate.map_or(0, |r| r as u16)).await?
-        }
-        TTSMode::Polly => {
-            polly::get_tts(
-                &state.polly,
-                text,
-                &voice,
-                speaking_rate.map(|r| r as u8),
-                preferred_format,
-            )
-            .await?
-        }
-        TTSMode::gCloud => {
-            gcloud::get_tts(
-                &state.gcloud,
-                &text,
-                &voice,
-                speaking_rate.unwrap_or(0.0),
-                preferred_format,
-            )
-            .await?
+    // The cache key is for the full text, so audio of cut down text is not cached.
+    let estimated_text = check_estimate(&state, &payload)?;
+    let redis_info = redis_info.filter(|_| estimated_text.is_none());
+    let text = estimated_text.as_deref().unwrap_or(&payload.text);
+
+    // The permits are released before `respond`, which takes its own if it needs to truncate.
+    let audio = {
+        // The family permit comes first, so requests queued for a busy family do not hold mode permits
+        // that other families could use.
+        let _family_permit = match mode {
+            TTSMode::gCloud => {
+                let family = gcloud::family(&payload.voice);
+                deadline
+                    .run(state.limits.acquire_gcloud_family(family))
+                    .await?
+            }
+            _ => None,
+        };
+        let _permit = deadline.run(state.limits.acquire(mode)).await?;
+        let (mut audio, _) = synthesize(&state, &payload, text, &audio_config, deadline).await?;
+
+        let native_content_type = mode.content_type(payload.preferred_format.as_deref());
+        if !filters.is_empty() || output.is_some_and(|output| !output.matches(native_content_type))
+        {
+            // Transcoding shares the deadline with synthesis, so a slow ffmpeg cannot hold the permit forever.
+            let transcode = audio::transcode(audio, native_content_type, output, &filters);
+            audio = deadline.run(transcode).await?;
         }
+
+        audio
     };
 
-    tracing::debug!("Generated TTS from {cache_key}");
+    tracing::debug!("Generated TTS from {log_key}");
     if let Some((mut redis_conn, key, cache_hash)) = redis_info {
+        let encrypted = tracing::info_span!("fernet_encrypt").in_scope(|| key.encrypt(&audio));
         if let Err(err) = redis_conn
-            .set::<_, _, ()>(&*cache_hash, key.encrypt(&audio))
+            .set::<_, _, ()>(&*cache_hash, encrypted)
+            .instrument(tracing::info_span!("cache_store"))
             .await
         {
+            metrics::get().redis_errors.inc();
             tracing::error!("Failed to cache: {err}");
         } else {
-            tracing::debug!("Cached TTS from {cache_key}");
-        };
+            tracing::debug!("Cached TTS from {log_key}");
+        }
+    }
+
+    respond(&state, audio, &content_type, &payload, deadline).await
+}
+
+/// Checks the `Authorization` header matches `auth_key`, if one is set.
+fn check_auth(state: &State, headers: &axum::http::HeaderMap) -> ResponseResult<()> {
+    if let Some(auth_key) = state.config.auth_key.as_deref() {
+        if headers
+            .get("Authorization")
+            .map(HeaderValue::to_str)
+            .transpose()?
+            != Some(auth_key)
+        {
+            return Err(Error::Unauthorized);
+        }
+    }
+
+    Ok(())
+}
+
+/// Parses the `X-Deadline` header, in milliseconds.
+fn parse_deadline(deadline: &HeaderValue) -> ResponseResult<Duration> {
+    deadline
+        .to_str()
+        .ok()
+        .and_then(|deadline| deadline.parse().ok())
+        .map(Duration::from_millis)
+        .ok_or_else(|| Error::InvalidDeadline(String::from_utf8_lossy(deadline.as_bytes()).into()))
+}
+
+/// When a request must be answered by, from the mode's timeout and `X-Deadline`.
+#[derive(Clone, Copy)]
+struct Deadline {
+    at: tokio::time::Instant,
+    timeout: Duration,
+}
+
+impl Deadline {
+    fn new(timeout: Duration) -> Self {
+        Self {
+            at: tokio::time::Instant::now() + timeout,
+            timeout,
+        }
+    }
+
+    /// Runs `future`, failing with [`Error::Timeout`] if the deadline passes first.
+    async fn run<T, E: Into<Error>>(
+        self,
+        future: impl std::future::Future<Output = Result<T, E>>,
+    ) -> ResponseResult<T> {
+        match tokio::time::timeout_at(self.at, future).await {
+            Ok(result) => result.map_err(Into::into),
+            Err(_) => Err(Error::Timeout(self.timeout)),
+        }
+    }
+}
+
+/// Checks the audio is within `max_length` seconds, truncating it if requested, then returns it with its duration.
+async fn respond(
+    state: &State,
+    mut audio: Bytes,
+    content_type: &str,
+    payload: &GetTTS,
+    deadline: Deadline,
+) -> ResponseResult<Response> {
+    let mut response = Response::builder().header(axum::http::header::CONTENT_TYPE, content_type);
+
+    match audio::duration(&audio, content_type) {
+        Ok(mut duration) => {
+            let max_length = payload.max_length.map(Duration::from_secs);
+            if let Some(max_length) = max_length.filter(|max_length| duration > *max_length) {
+                if !payload.truncate {
+                    return Err(Error::AudioTooLong);
+                }
+
+                // Truncating runs ffmpeg, so is limited and bounded like synthesis, even for cached audio.
+                let _permit = deadline.run(state.limits.acquire(payload.mode)).await?;
+                let fade_out = payload.fade_out.map(|ms| Duration::from_millis(ms.into()));
+                let truncate = audio::truncate(audio, content_type, max_length, fade_out);
+                audio = deadline.run(truncate).await?;
+                duration = audio::duration(&audio, content_type)?;
+                response = response.header("X-Audio-Truncated", "true");
+            }
+
+            let duration = format!("{:.3}", duration.as_secs_f64());
+            response = response.header("X-Audio-Duration", duration);
+        }
+        // Without a duration, `max_length` cannot be enforced so the audio is not returned.
+        Err(err) if payload.max_length.is_some() => {
+            return Err(err
+                .context(format!(
+                    "Could not check max_length of {content_type} audio"
+                ))
+                .into());
+        }
+        Err(err) => tracing::warn!("Could not find duration of {content_type} audio: {err:#}"),
+    }
+
+    response
+        .body(axum::body::Body::from(audio))
+        .map_err(Into::into)
+}
+
+/// The loudness to normalize to, from the request if given or else the mode's config.
+fn loudness_target(state: &State, payload: &GetTTS) -> ResponseResult<Option<f64>> {
+    let Some(loudness) = payload.loudness.as_deref() else {
+        return Ok(state.config.loudness(payload.mode));
     };
 
-    mode.check_length(&audio, payload.max_length)?;
-    mode.into_response(audio, content_type)
+    if loudness == "off" {
+        return Ok(None);
+    }
+
+    let target = loudness.parse().map_err(|_| {
+        Error::InvalidFormat(format!(
+            "loudness must be a number or off, not {loudness:?}"
+        ))
+    })?;
+
+    audio::check_loudness(target).map_err(|err| Error::InvalidFormat(format!("loudness {err}")))?;
+    Ok(Some(target))
+}
+
+/// Checks the gCloud `audioConfig` parameters, which no other mode can honour.
+fn gcloud_audio_config(payload: &GetTTS) -> ResponseResult<gcloud::AudioConfig> {
+    let params = [
+        ("pitch", payload.pitch.is_some()),
+        ("volume_gain_db", payload.volume_gain_db.is_some()),
+        ("sample_rate_hertz", payload.sample_rate_hertz.is_some()),
+        ("effects_profile_id", payload.effects_profile_id.is_some()),
+    ];
+
+    if !matches!(payload.mode, TTSMode::gCloud) {
+        if let Some((param, _)) = params.iter().find(|(_, is_set)| *is_set) {
+            return Err(Error::InvalidAudioConfig(format!(
+                "{param} is only supported by {}",
+                TTSMode::gCloud
+            )));
+        }
+
+        return Ok(gcloud::AudioConfig::default());
+    }
+
+    if payload.sample_rate_hertz.is_some() && payload.sample_rate.is_some() {
+        return Err(Error::InvalidAudioConfig(String::from(
+            "sample_rate_hertz cannot be combined with sample_rate",
+        )));
+    }
+
+    gcloud::AudioConfig::new(
+        payload.pitch,
+        payload.volume_gain_db,
+        payload.sample_rate_hertz,
+        payload.effects_profile_id.as_deref(),
+        payload.preferred_format.as_deref(),
+    )
+    .map_err(|err| Error::InvalidAudioConfig(err.to_string()))
+}
+
+/// Rejects text that is expected to be well over `max_length` before paying for synthesis, or
+/// returns a truncated copy of it if `truncate` is set.
+fn check_estimate(state: &State, payload: &GetTTS) -> ResponseResult<Option<String>> {
+    let limits = &state.config.limits;
+    let Some(max_length) = payload.max_length.filter(|_| limits.estimate_length) else {
+        return Ok(None);
+    };
+
+    let estimator = estimate::get();
+    let mode = payload.mode;
+    let max_length = Duration::from_secs(max_length).mul_f64(1.0 + limits.estimate_margin);
+    let estimate = estimator.estimate(mode, &payload.voice, &payload.text, payload.speaking_rate);
+    if estimate <= max_length {
+        return Ok(None);
+    }
+
+    let action = if payload.truncate {
+        "truncated"
+    } else {
+        "rejected"
+    };
+    metrics::get()
+        .estimated_too_long
+        .with_label_values(&[&mode.to_string(), action])
+        .inc();
+
+    if !payload.truncate {
+        return Err(Error::AudioTooLong);
+    }
+
+    let text = estimator.truncate(
+        mode,
+        &payload.voice,
+        &payload.text,
+        payload.speaking_rate,
+        max_length,
+    );
+
+    if text.is_empty() {
+        return Err(Error::AudioTooLong);
+    }
+
+    tracing::debug!("Truncated text from an estimated {estimate:?} to {max_length:?}");
+    Ok(Some(text.to_owned()))
+}
+
+async fn synthesize(
+    state: &State,
+    payload: &GetTTS,
+    text: &str,
+    audio_config: &gcloud::AudioConfig,
+    deadline: Deadline,
+) -> ResponseResult<(Bytes, Option<reqwest::header::HeaderValue>)> {
+    let GetTTS {
+        mode,
+        voice,
+        speaking_rate,
+        preferred_format,
+        ..
+    } = payload;
+
+    // Dropping the provider future on expiry cancels any in-flight HTTP requests
+    // and kills the espeak/mbrola children, as they are spawned with `kill_on_drop`.
+    let synthesis = async {
+        match mode {
+            TTSMode::gTTS => {
+                let chunk_concurrency = state.config.gtts.chunk_concurrency;
+                gtts::get_tts(state.gtts(), text, voice, *speaking_rate, chunk_concurrency).await
+            }
+            TTSMode::eSpeak => {
+                espeak::get_tts(text, voice, speaking_rate.map_or(0, |r| r as u16)).await
+            }
+            TTSMode::Polly => {
+                polly::get_tts(
+                    state.polly(),
+                    text.to_owned(),
+                    voice,
+                    speaking_rate.map(|r| r as u8),
+                    preferred_format.clone(),
+                )
+                .await
+            }
+            TTSMode::gCloud => {
+                gcloud::get_tts(
+                    state.gcloud(),
+                    text,
+                    voice,
+                    speaking_rate.unwrap_or(0.0),
+                    preferred_format.clone(),
+                    audio_config,
+                )
+                .await
+            }
+        }
+    };
+
+    let metrics = metrics::get();
+    let mode_label = mode.to_string();
+    let synthesis = synthesis.instrument(tracing::info_span!(
+        "synthesize",
+        mode = %mode,
+        voice = voice.as_str(),
+        text_length = text.len(),
+    ));
+
+    let synthesis_start = std::time::Instant::now();
+    let result = deadline.run(synthesis).await;
+
+    let outcome = if result.is_ok() { "ok" } else { "error" };
+    metrics
+        .synthesis_seconds
+        .with_label_values(&[&mode_label, outcome])
+        .observe(synthesis_start.elapsed().as_secs_f64());
+
+    let (audio, content_type) = result?;
+    metrics
+        .audio_bytes
+        .with_label_values(&[&mode_label])
+        .inc_by(audio.len() as u64);
+
+    let native_content_type = mode.content_type(preferred_format.as_deref());
+    if let Ok(duration) = audio::duration(&audio, native_content_type) {
+        estimate::get().observe(*mode, voice, text, *speaking_rate, duration);
+        metrics
+            .audio_seconds
+            .with_label_values(&[&mode_label])
+            .inc_by(duration.as_secs_f64());
+    }
+
+    Ok((audio, content_type))
 }
 
 #[derive(serde::Deserialize, Clone, Copy, Debug)]
@@ -192,34 +626,12 @@ enum TTSMode {
 }
 
 impl TTSMode {
-    #[allow(clippy::unused_self)]
-    fn into_response(
-        self,
-        data: Bytes,
-        _: Option<reqwest::header::HeaderValue>,
-    ) -> ResponseResult<Response> {
-        Response::builder()
-            // TODO: Re-add when reqwest updates http to 1.0
-            // .header(axum::http::header::CONTENT_TYPE, content_type.unwrap_or_else(|| HeaderValue::from_static(match self {
-            //     #[cfg(feature="gtts")]    Self::gTTS    => "audio/mpeg",
-            //     #[cfg(feature="espeak")]  Self::eSpeak  => "audio/wav",
-            //     #[cfg(feature="gcloud")]  Self::gCloud  => "audio/opus",
-            //     #[cfg(feature="polly")]   Self::Polly   => "audio/ogg",
-            // })))
-            .body(axum::body::Body::from(data))
-            .map_err(Into::into)
-    }
-
-    #[cfg_attr(
-        not(feature = "polly"),
-        allow(unused_variables, clippy::unnecessary_wraps)
-    )]
     async fn check_voice(self, state: &State, voice: String) -> ResponseResult<String> {
         if match self {
             Self::gTTS => gtts::check_voice(&voice),
             Self::eSpeak => espeak::check_voice(&voice),
-            Self::gCloud => gcloud::check_voice(&state.gcloud, &voice).await?,
-            Self::Polly => polly::check_voice(&state.polly, &voice).await?,
+            Self::gCloud => gcloud::check_voice(state.gcloud(), &voice).await?,
+            Self::Polly => polly::check_voice(state.polly(), &voice).await?,
         } {
             Ok(voice)
         } else {
@@ -227,16 +639,48 @@ impl TTSMode {
         }
     }
 
-    #[allow(unused_variables)]
-    fn check_length(self, audio: &[u8], max_length: Option<u64>) -> ResponseResult<()> {
-        if max_length.map_or(true, |max_length| match self {
-            Self::gTTS => check_mp3_length(audio, max_length),
-            Self::eSpeak => espeak::check_length(audio, max_length as u32),
-            Self::gCloud | Self::Polly => true,
-        }) {
+    const fn env_prefix(self) -> &'static str {
+        match self {
+            Self::gTTS => "GTTS",
+            Self::Polly => "POLLY",
+            Self::eSpeak => "ESPEAK",
+            Self::gCloud => "GCLOUD",
+        }
+    }
+
+    /// The content type of audio generated with `preferred_format`.
+    fn content_type(self, preferred_format: Option<&str>) -> &'static str {
+        match self {
+            Self::gTTS => "audio/mpeg",
+            Self::eSpeak => "audio/wav",
+            Self::Polly => polly::content_type(preferred_format),
+            Self::gCloud => gcloud::content_type(preferred_format),
+        }
+    }
+
+    /// The format to request from the provider to avoid transcoding into `format` where possible.
+    const fn native_format(self, format: audio::Format) -> Option<&'static str> {
+        use audio::Format;
+        match self {
+            Self::gTTS | Self::eSpeak => None,
+            Self::Polly => match format {
+                Format::Mp3 => Some("mp3"),
+                Format::OggVorbis => Some("ogg_vorbis"),
+                Format::Wav | Format::OggOpus | Format::Pcm => Some("pcm"),
+            },
+            Self::gCloud => match format {
+                Format::Mp3 => Some("MP3"),
+                Format::OggOpus => Some("OGG_OPUS"),
+                Format::Wav | Format::OggVorbis | Format::Pcm => Some("LINEAR16"),
+            },
+        }
+    }
+
+    fn check_enabled(self, state: &State) -> ResponseResult<()> {
+        if state.config.enabled(self) {
             Ok(())
         } else {
-            Err(Error::AudioTooLong)
+            Err(Error::ModeDisabled(self))
         }
     }
 
@@ -280,93 +724,295 @@ struct RedisCache {
 }
 
 struct State {
-    auth_key: Option<String>,
+    config: config::Config,
+    limits: Arc<limits::Limits>,
     redis: Option<RedisCache>,
-    polly: polly::State,
-    gtts: tokio::sync::RwLock<gtts::State>,
-    gcloud: tokio::sync::RwLock<gcloud::State>,
+    polly: Option<polly::State>,
+    gtts: Option<Arc<gtts::State>>,
+    gcloud: Option<tokio::sync::RwLock<gcloud::State>>,
+    remote_checks: health::RemoteChecks,
 }
 
-static STATE: OnceLock<State> = OnceLock::new();
+impl State {
+    /// Sets up each enabled mode, reusing the limiters and gTTS address pool from `previous` if their settings are unchanged.
+    async fn new(config: config::Config, previous: Option<&Self>) -> Result<Self> {
+        let polly = if config.polly.mode.enabled {
+            let polly_config = aws_config::from_env()
+                .timeout_config(
+                    aws_config::timeout::TimeoutConfig::builder()
+                        .operation_timeout(config.timeout(TTSMode::Polly))
+                        .build(),
+                )
+                .load()
+                .await;
+
+            Some(polly::State::new(&polly_config))
+        } else {
+            None
+        };
+
+        let limits = match previous {
+            Some(previous) if previous.limits.matches(&config) => previous.limits.clone(),
+            _ => Arc::new(limits::Limits::new(&config)?),
+        };
+
+        let gtts_timeout = config.timeout(TTSMode::gTTS);
+        let previous_gtts = previous
+            .and_then(|previous| previous.gtts.as_ref())
+            .filter(|gtts| gtts.matches(&config.gtts, gtts_timeout));
+
+        let gtts = if let (true, Some(previous_gtts)) = (config.gtts.mode.enabled, previous_gtts) {
+            Some(previous_gtts.clone())
+        } else if config.gtts.mode.enabled {
+            Some(gtts::State::new(&config.gtts, gtts_timeout).await?)
+        } else {
+            None
+        };
+
+        let gcloud = match &config.gcloud.credentials {
+            Some(credentials) if config.gcloud.mode.enabled => {
+                let service_account = gcloud::ServiceAccount::load(credentials.as_ref())?;
+                let reqwest = reqwest::Client::builder()
+                    .timeout(config.timeout(TTSMode::gCloud))
+                    .build()?;
+
+                Some(gcloud::State::new(reqwest, service_account)?)
+            }
+            _ => None,
+        };
+
+        let redis = if let Some(cache) = &config.cache {
+            Some(RedisCache {
+                client: deadpool_redis::Config::from_url(&cache.redis_uri)
+                    .create_pool(Some(deadpool_redis::Runtime::Tokio1))?,
+                key: fernet::Fernet::new(&cache.key).unwrap(),
+            })
+        } else {
+            None
+        };
+
+        Ok(Self {
+            config,
+            limits,
+            redis,
+            polly,
+            gtts,
+            gcloud,
+            remote_checks: health::RemoteChecks::default(),
+        })
+    }
+
+    // The following panic if the mode is disabled, so `TTSMode::check_enabled` must be called first.
+    fn polly(&self) -> &polly::State {
+        self.polly.as_ref().expect("Polly should be enabled")
+    }
+
+    fn gtts(&self) -> &gtts::State {
+        self.gtts.as_ref().expect("gTTS should be enabled")
+    }
+
+    fn gcloud(&self) -> &tokio::sync::RwLock<gcloud::State> {
+        self.gcloud.as_ref().expect("gCloud should be enabled")
+    }
+}
+
+/// The current state, swapped out on reload. Requests hold onto the state they started with.
+static STATE: OnceLock<ArcSwap<State>> = OnceLock::new();
+
+fn get_state() -> Arc<State> {
+    STATE.get().unwrap().load_full()
+}
+
+async fn reload_state(config_path: Option<&std::path::Path>) -> Result<()> {
+    let config = config::Config::load(config_path)?;
+    let current = get_state();
+
+    if config.bind_addr != current.config.bind_addr
+        || config.tls != current.config.tls
+        || config.log.level != current.config.log.level
+        || config.log.format != current.config.log.format
+    {
+        tracing::warn!(
+            "Changes to bind_addr, the TLS file paths or log level/format require a restart to apply"
+        );
+    }
+
+    let state = State::new(config, Some(&current)).await?;
+    STATE.get().unwrap().store(Arc::new(state));
+    Ok(())
+}
+
+async fn reload_on_sighup(config_path: Option<std::path::PathBuf>) {
+    let mut sighup =
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).unwrap();
+
+    while sighup.recv().await.is_some() {
+        tracing::info!("Received SIGHUP, reloading configuration");
+        match reload_state(config_path.as_deref()).await {
+            Ok(()) => tracing::info!("Reloaded configuration"),
+            Err(err) => tracing::error!("Failed to reload, keeping current configuration: {err:?}"),
+        }
+    }
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let fmt_layer = tracing_subscriber::fmt::layer();
-    let filter = tracing_subscriber::filter::LevelFilter::from_str(
-        &std::env::var("LOG_LEVEL").unwrap_or_else(|_| String::from("INFO")),
-    )?;
+    let mut config_path = std::env::var_os("CONFIG_PATH").map(std::path::PathBuf::from);
+    let mut check_config = false;
 
-    tracing_subscriber::registry()
-        .with(fmt_layer)
-        .with(filter)
-        .init();
-
-    let redis_uri = std::env::var("REDIS_URI").ok();
-    let result = STATE.set(State {
-        gcloud: gcloud::State::new(reqwest::Client::new())?,
-        gtts: tokio::sync::RwLock::new(gtts::get_random_ipv6().await?),
-        polly: polly::State::new(&aws_config::load_from_env().await),
-
-        auth_key: std::env::var("AUTH_KEY").ok(),
-        redis: redis_uri.as_ref().map(|uri| {
-            let key = std::env::var("CACHE_KEY").expect("CACHE_KEY not set!");
-            RedisCache {
-                client: deadpool_redis::Config::from_url(uri)
-                    .create_pool(Some(deadpool_redis::Runtime::Tokio1))
-                    .unwrap(),
-                key: fernet::Fernet::new(&key).unwrap(),
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => {
+                let path = args
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("--config requires a path"))?;
+                config_path = Some(path.into());
             }
-        }),
-    });
+            "--check-config" => check_config = true,
+            _ => anyhow::bail!("Unknown argument: {arg}"),
+        }
+    }
+
+    let config = config::Config::load(config_path.as_deref())?;
+    if check_config {
+        println!("Configuration is valid");
+        return Ok(());
+    }
+
+    telemetry::init(&config.log)?;
+
+    let bind_to = config.bind_addr.clone();
+    let tls = config.tls.clone();
+    let grace_period = config.shutdown_grace_period();
+    let redis_enabled = config.cache.is_some();
+
+    let result = STATE.set(ArcSwap::from_pointee(State::new(config, None).await?));
     if result.is_err() {
         unreachable!()
     }
 
+    tokio::spawn(reload_on_sighup(config_path));
+
     let app = axum::Router::new()
         .route("/tts", axum::routing::get(get_tts))
         .route("/voices", axum::routing::get(get_voices))
+        .route("/gtts/pool", axum::routing::get(get_gtts_pool))
+        .route("/health", axum::routing::get(health::health))
+        .route("/ready", axum::routing::get(health::ready))
+        .route("/metrics", axum::routing::get(metrics::handler))
         .route(
             "/modes",
             axum::routing::get(|| async {
-                axum::Json([
-                    TTSMode::gTTS.to_string(),
-                    TTSMode::Polly.to_string(),
-                    TTSMode::eSpeak.to_string(),
-                    TTSMode::gCloud.to_string(),
-                ])
-            }),
-        );
+                let config = &get_state().config;
+                let modes: Vec<_> = [
+                    TTSMode::gTTS,
+                    TTSMode::Polly,
+                    TTSMode::eSpeak,
+                    TTSMode::gCloud,
+                ]
+                .into_iter()
+                .filter(|mode| config.enabled(*mode))
+                .map(|mode| mode.to_string())
+                .collect();
 
-    let env_addr = std::env::var("BIND_ADDR");
-    let bind_to = env_addr.as_deref().unwrap_or("0.0.0.0:3000");
+                axum::Json(modes)
+            }),
+        )
+        .layer(axum::middleware::from_fn(telemetry::request_span));
 
     tracing::info!(
-        "Binding to {bind_to} {} redis enabled!",
-        if redis_uri.is_some() {
-            "with"
+        "Binding to {bind_to} {} TLS, {} redis enabled!",
+        if tls.is_some() { "with" } else { "without" },
+        if redis_enabled { "with" } else { "without" }
+    );
+
+    let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
+    let server = server::serve(&bind_to, tls.as_ref(), app, async move {
+        shutdown_signal().await;
+        let _ = shutdown_tx.send(());
+    });
+
+    let grace_period_expired = async move {
+        if shutdown_rx.await.is_ok() {
+            tracing::info!("Shutting down, waiting up to {grace_period:?} for requests to finish");
+            tokio::time::sleep(grace_period).await;
         } else {
-            "without"
+            std::future::pending::<()>().await;
         }
-    );
+    };
+
+    tokio::select! {
+        result = server => result?,
+        () = grace_period_expired => {
+            // Returning drops the runtime and with it the in-flight requests, which
+            // kills any leftover espeak/mbrola children due to `kill_on_drop`.
+            tracing::warn!("Grace period expired, cancelling remaining requests");
+        }
+    };
 
-    let listener = tokio::net::TcpListener::bind(bind_to).await?;
-    axum::serve(listener, app.into_make_service()).await?;
+    telemetry::shutdown();
     Ok(())
 }
 
+async fn shutdown_signal() {
+    let mut sigterm =
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).unwrap();
+
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result.unwrap(),
+        _ = sigterm.recv() => {},
+    }
+}
+
 #[derive(Debug)]
 enum Error {
     Unauthorized,
     UnknownVoice(String),
     AudioTooLong,
     InvalidSpeakingRate(f32),
+    Timeout(Duration),
+    Overloaded,
+    ModeDisabled(TTSMode),
+    InvalidFormat(String),
+    InvalidAudioConfig(String),
+    InvalidDeadline(String),
 
     Unknown(anyhow::Error),
 }
 
+impl Error {
+    /// A stable name for the error, used as a metrics label.
+    const fn kind(&self) -> &'static str {
+        match self {
+            Self::Unauthorized => "unauthorized",
+            Self::UnknownVoice(_) => "unknown_voice",
+            Self::AudioTooLong => "audio_too_long",
+            Self::InvalidSpeakingRate(_) => "invalid_speaking_rate",
+            Self::Timeout(_) => "timeout",
+            Self::Overloaded => "overloaded",
+            Self::ModeDisabled(_) => "mode_disabled",
+            Self::InvalidFormat(_) => "invalid_format",
+            Self::InvalidAudioConfig(_) => "invalid_audio_config",
+            Self::InvalidDeadline(_) => "invalid_deadline",
+            Self::Unknown(_) => "unknown",
+        }
+    }
+}
+
 impl<E: Into<anyhow::Error>> From<E> for Error {
     fn from(e: E) -> Self {
-        Self::Unknown(e.into())
+        let mut err = e.into();
+
+        // gTTS sends the message text in the query string, which reqwest includes in its errors.
+        let url = err
+            .downcast_mut::<reqwest::Error>()
+            .and_then(reqwest::Error::url_mut);
+        if let Some(url) = url {
+            url.set_query(None);
+        }
+
+        Self::Unknown(err)
     }
 }
 
@@ -377,6 +1023,17 @@ impl std::fmt::Display for Error {
             Self::AudioTooLong => f.write_str("Max length exceeded!"),
             Self::UnknownVoice(voice) => write!(f, "Unknown voice: {voice}"),
             Self::Unauthorized => write!(f, "Unauthorized request"),
+            Self::Timeout(timeout) => write!(f, "Deadline of {timeout:?} exceeded"),
+            Self::Overloaded => f.write_str("Too many requests queued, try again later"),
+            Self::ModeDisabled(mode) => write!(f, "{mode} is not enabled"),
+            Self::InvalidFormat(reason) => write!(f, "Invalid format: {reason}"),
+            Self::InvalidAudioConfig(reason) => write!(f, "Invalid audio config: {reason}"),
+            Self::InvalidDeadline(deadline) => {
+                write!(
+                    f,
+                    "Invalid X-Deadline: {deadline:?} is not a number of milliseconds"
+                )
+            }
             Self::Unknown(e) => write!(f, "Unknown error: {e}"),
         }
     }
@@ -386,11 +1043,17 @@ impl axum::response::IntoResponse for Error {
     fn into_response(self) -> Response {
         if let Error::Unknown(inner) = &self {
             tracing::error!("{inner:?}");
-        };
+        }
 
         let json_err = serde_json::json!({
             "display": self.to_string(),
             "code": match self {
+                Self::InvalidDeadline(_) => 10,
+                Self::InvalidAudioConfig(_) => 9,
+                Self::InvalidFormat(_) => 8,
+                Self::ModeDisabled(_) => 7,
+                Self::Overloaded => 6,
+                Self::Timeout(_) => 5,
                 Self::Unauthorized => 4,
                 Self::InvalidSpeakingRate(_) => 3_u8,
                 Self::AudioTooLong => 2,
@@ -400,14 +1063,110 @@ impl axum::response::IntoResponse for Error {
         });
 
         let status = match self {
-            Self::AudioTooLong | Self::InvalidSpeakingRate(_) => {
-                axum::http::StatusCode::BAD_REQUEST
-            }
+            Self::AudioTooLong
+            | Self::InvalidSpeakingRate(_)
+            | Self::UnknownVoice(_)
+            | Self::ModeDisabled(_)
+            | Self::InvalidFormat(_)
+            | Self::InvalidAudioConfig(_)
+            | Self::InvalidDeadline(_) => axum::http::StatusCode::BAD_REQUEST,
             Self::Unknown(_) => axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-            Self::UnknownVoice(_) => axum::http::StatusCode::BAD_REQUEST,
             Self::Unauthorized => axum::http::StatusCode::FORBIDDEN,
+            Self::Timeout(_) => axum::http::StatusCode::GATEWAY_TIMEOUT,
+            Self::Overloaded => axum::http::StatusCode::SERVICE_UNAVAILABLE,
         };
 
         (status, axum::Json(json_err)).into_response()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{gcloud_audio_config, Deadline, Error, GetTTS};
+
+    fn payload(query: &str) -> GetTTS {
+        let uri = format!("/tts?text=Hello&lang=en-US%20A&{query}")
+            .parse()
+            .unwrap();
+        axum::extract::Query::try_from_uri(&uri).unwrap().0
+    }
+
+    fn rejection(query: &str) -> String {
+        match gcloud_audio_config(&payload(query)) {
+            Err(Error::InvalidAudioConfig(message)) => message,
+            Err(err) => panic!("{query} failed with {err}, not InvalidAudioConfig"),
+            Ok(_) => panic!("{query} was accepted"),
+        }
+    }
+
+    #[test]
+    fn rejects_gcloud_settings_for_other_modes() {
+        for mode in ["gTTS", "Polly", "eSpeak"] {
+            assert!(gcloud_audio_config(&payload(&format!("mode={mode}"))).is_ok());
+
+            for param in ["pitch=1", "volume_gain_db=1", "sample_rate_hertz=24000"] {
+                let name = param.split_once('=').unwrap().0;
+                assert_eq!(
+                    rejection(&format!("mode={mode}&{param}")),
+                    format!("{name} is only supported by gCloud")
+                );
+            }
+
+            assert_eq!(
+                rejection(&format!(
+                    "mode={mode}&effects_profile_id=handset-class-device"
+                )),
+                "effects_profile_id is only supported by gCloud"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_sample_rate_hertz_with_sample_rate() {
+        assert_eq!(
+            rejection("mode=gCloud&sample_rate_hertz=24000&sample_rate=24000"),
+            "sample_rate_hertz cannot be combined with sample_rate"
+        );
+        assert!(gcloud_audio_config(&payload("mode=gCloud&sample_rate=22050")).is_ok());
+    }
+
+    #[test]
+    fn checks_gcloud_settings() {
+        let config =
+            gcloud_audio_config(&payload("mode=gCloud&pitch=2&volume_gain_db=-6")).unwrap();
+        assert_eq!(config.to_string(), "pitch 2 gain -6dB rate 0Hz effects ");
+
+        assert_eq!(
+            rejection("mode=gCloud&sample_rate_hertz=22050"),
+            "sample_rate_hertz of 22050 is not supported by OGG_OPUS"
+        );
+        assert!(gcloud_audio_config(&payload(
+            "mode=gCloud&sample_rate_hertz=22050&preferred_format=mp3"
+        ))
+        .is_ok());
+    }
+
+    #[tokio::test]
+    async fn deadline_counts_time_before_and_while_queueing() {
+        let timeout = Duration::from_millis(100);
+        let deadline = Deadline::new(timeout);
+        let start = std::time::Instant::now();
+        tokio::time::sleep(Duration::from_millis(60)).await;
+
+        let permits = tokio::sync::Semaphore::new(1);
+        let _held = permits.acquire().await.unwrap();
+        match deadline.run(permits.acquire()).await {
+            Err(Error::Timeout(expired)) => assert_eq!(expired, timeout),
+            Err(err) => panic!("failed with {err}, not a timeout"),
+            Ok(_) => panic!("acquired a held permit"),
+        }
+
+        assert!(
+            start.elapsed() < Duration::from_millis(200),
+            "{:?}",
+            start.elapsed()
+        );
+    }
+}
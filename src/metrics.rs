@@ -0,0 +1,115 @@
+use std::sync::OnceLock;
+
+use prometheus::{
+    core::Collector, exponential_buckets, CounterVec, Encoder, HistogramOpts, HistogramVec,
+    IntCounter, IntCounterVec, Opts, Registry, TextEncoder,
+};
+
+use crate::{ResponseResult, Result};
+
+pub struct Metrics {
+    registry: Registry,
+    /// Requests to `/tts`, labelled by `mode` and `outcome` (`ok` or `error`).
+    pub requests: IntCounterVec,
+    /// Failed requests to `/tts`, labelled by `mode` and `error` kind.
+    pub errors: IntCounterVec,
+    /// Time spent calling the provider, labelled by `mode` and `outcome`.
+    pub synthesis_seconds: HistogramVec,
+    /// Bytes of audio generated by the provider, labelled by `mode`.
+    pub audio_bytes: IntCounterVec,
+    /// Seconds of audio generated by the provider, labelled by `mode`.
+    pub audio_seconds: CounterVec,
+    /// Cache lookups, labelled by `mode` and `result` (`hit` or `miss`).
+    pub cache_lookups: IntCounterVec,
+    /// Requests estimated to exceed `max_length` before synthesis, labelled by `mode` and `action` (`rejected` or `truncated`).
+    pub estimated_too_long: IntCounterVec,
+    pub redis_errors: IntCounter,
+    pub gtts_ip_rotations: IntCounter,
+    pub espeak_retries: IntCounter,
+}
+
+impl Metrics {
+    fn new() -> Result<Self> {
+        let registry = Registry::new_custom(Some(String::from("tts")), None)?;
+        let metrics = Self {
+            requests: IntCounterVec::new(
+                Opts::new("requests_total", "Requests to generate TTS"),
+                &["mode", "outcome"],
+            )?,
+            errors: IntCounterVec::new(
+                Opts::new("errors_total", "Errors returned when generating TTS"),
+                &["mode", "error"],
+            )?,
+            synthesis_seconds: HistogramVec::new(
+                HistogramOpts::new("synthesis_seconds", "Time spent generating TTS")
+                    .buckets(exponential_buckets(0.05, 2.0, 10)?),
+                &["mode", "outcome"],
+            )?,
+            audio_bytes: IntCounterVec::new(
+                Opts::new("audio_bytes_total", "Bytes of audio generated"),
+                &["mode"],
+            )?,
+            audio_seconds: CounterVec::new(
+                Opts::new("audio_seconds_total", "Seconds of audio generated"),
+                &["mode"],
+            )?,
+            cache_lookups: IntCounterVec::new(
+                Opts::new("cache_lookups_total", "Lookups of the TTS cache"),
+                &["mode", "result"],
+            )?,
+            estimated_too_long: IntCounterVec::new(
+                Opts::new(
+                    "estimated_too_long_total",
+                    "Requests estimated to exceed max_length before synthesis",
+                ),
+                &["mode", "action"],
+            )?,
+            redis_errors: IntCounter::new("redis_errors_total", "Errors talking to redis")?,
+            gtts_ip_rotations: IntCounter::new(
+                "gtts_ip_rotations_total",
+                "Times a gTTS IP was blocked and rested for its cooldown",
+            )?,
+            espeak_retries: IntCounter::new(
+                "espeak_retries_total",
+                "Times eSpeak was retried due to a missing WAV header",
+            )?,
+            registry,
+        };
+
+        let collectors: [Box<dyn Collector>; 10] = [
+            Box::new(metrics.requests.clone()),
+            Box::new(metrics.errors.clone()),
+            Box::new(metrics.synthesis_seconds.clone()),
+            Box::new(metrics.audio_bytes.clone()),
+            Box::new(metrics.audio_seconds.clone()),
+            Box::new(metrics.cache_lookups.clone()),
+            Box::new(metrics.estimated_too_long.clone()),
+            Box::new(metrics.redis_errors.clone()),
+            Box::new(metrics.gtts_ip_rotations.clone()),
+            Box::new(metrics.espeak_retries.clone()),
+        ];
+
+        for collector in collectors {
+            metrics.registry.register(collector)?;
+        }
+
+        Ok(metrics)
+    }
+}
+
+pub fn get() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(|| Metrics::new().expect("Failed to register metrics"))
+}
+
+/// Counts a redis error, for use with `map_err`.
+pub fn redis_error<E>(err: E) -> E {
+    get().redis_errors.inc();
+    err
+}
+
+pub async fn handler() -> ResponseResult<String> {
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&get().registry.gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
@@ -0,0 +1,318 @@
+use crate::Result;
+
+/// Sample rates by MPEG version, indexed by the header's sample rate bits.
+const SAMPLE_RATES: [[u32; 3]; 3] = [
+    [44100, 48000, 32000], // MPEG-1
+    [22050, 24000, 16000], // MPEG-2
+    [11025, 12000, 8000],  // MPEG-2.5
+];
+
+/// Layer III bitrates in kbps, for MPEG-1 then MPEG-2 and 2.5, indexed by the header's bitrate bits.
+const BITRATES: [[u32; 15]; 2] = [
+    [
+        0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+    ],
+    [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+];
+
+/// Length of an `ID3v1` tag, which sits at the end of the file.
+const ID3V1_LEN: usize = 128;
+
+/// Xing header flags for the frame and byte counts.
+const XING_FLAGS: u32 = 0b11;
+
+/// The length of the `ID3v2` tag at the start of `audio`, or 0 if there is none.
+pub fn id3v2_len(audio: &[u8]) -> usize {
+    match audio.get(..10) {
+        Some([b'I', b'D', b'3', _, _, flags, size @ ..]) => {
+            // The size is "syncsafe", using 7 bits per byte, and excludes the header and footer.
+            let size = size
+                .iter()
+                .fold(0, |size, byte| (size << 7) | usize::from(byte & 0x7F));
+            let footer = if flags & 0x10 == 0 { 0 } else { 10 };
+
+            10 + size + footer
+        }
+        _ => 0,
+    }
+}
+
+/// A parsed MPEG Layer III frame header.
+#[derive(Clone, Copy)]
+struct Header {
+    bytes: [u8; 4],
+    /// 0 for MPEG-1, 1 for MPEG-2 and 2 for MPEG-2.5.
+    version: usize,
+    bitrate_index: usize,
+    sample_rate: u32,
+}
+
+impl Header {
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        let bytes: [u8; 4] = bytes.get(..4)?.try_into().ok()?;
+        if bytes[0] != 0xFF || bytes[1] & 0xE0 != 0xE0 {
+            return None;
+        }
+
+        let version = match (bytes[1] >> 3) & 0b11 {
+            0b11 => 0,
+            0b10 => 1,
+            0b00 => 2,
+            _ => return None,
+        };
+
+        // Only Layer III is produced by the TTS providers.
+        if (bytes[1] >> 1) & 0b11 != 0b01 {
+            return None;
+        }
+
+        // Free format (0) and the invalid index (15) cannot be framed.
+        let bitrate_index = usize::from(bytes[2] >> 4);
+        if bitrate_index == 0 || bitrate_index == 15 {
+            return None;
+        }
+
+        let sample_rate = *SAMPLE_RATES[version].get(usize::from((bytes[2] >> 2) & 0b11))?;
+        Some(Self {
+            bytes,
+            version,
+            bitrate_index,
+            sample_rate,
+        })
+    }
+
+    fn bitrate(self) -> u32 {
+        BITRATES[self.version.min(1)][self.bitrate_index] * 1000
+    }
+
+    fn is_mono(self) -> bool {
+        self.bytes[3] >> 6 == 0b11
+    }
+
+    fn has_crc(self) -> bool {
+        self.bytes[1] & 1 == 0
+    }
+
+    fn len(self) -> usize {
+        let samples = if self.version == 0 { 1152 } else { 576 };
+        let padding = usize::from((self.bytes[2] >> 1) & 1);
+
+        (samples / 8 * self.bitrate() / self.sample_rate) as usize + padding
+    }
+
+    /// The offset of a Xing or Info header in the frame, after the side information.
+    fn xing_offset(self) -> usize {
+        let side_info_len = match (self.version == 0, self.is_mono()) {
+            (true, false) => 32,
+            (true, true) | (false, false) => 17,
+            (false, true) => 9,
+        };
+
+        4 + usize::from(self.has_crc()) * 2 + side_info_len
+    }
+}
+
+/// The sample rate of the first MPEG audio frame, after any `ID3v2` tag.
+pub fn sample_rate(audio: &[u8]) -> Option<u32> {
+    Header::parse(audio.get(id3v2_len(audio)..)?).map(|header| header.sample_rate)
+}
+
+/// Checks if a frame holds a Xing, Info or VBRI header instead of audio.
+fn is_metadata_frame(header: Header, frame: &[u8]) -> bool {
+    let tag = frame.get(header.xing_offset()..header.xing_offset() + 4);
+    matches!(tag, Some(b"Xing" | b"Info")) || frame.get(36..40) == Some(b"VBRI")
+}
+
+/// The audio frames of an MPEG stream, skipping tags, Xing headers and any bytes between frames.
+fn audio_frames(audio: &[u8]) -> impl Iterator<Item = (Header, &[u8])> {
+    let mut end = audio.len();
+    if end >= ID3V1_LEN && audio[end - ID3V1_LEN..].starts_with(b"TAG") {
+        end -= ID3V1_LEN;
+    }
+
+    let audio = &audio[..end];
+    let mut offset = id3v2_len(audio);
+    std::iter::from_fn(move || {
+        while offset < audio.len() {
+            let frame = Header::parse(&audio[offset..])
+                .and_then(|header| Some((header, audio.get(offset..offset + header.len())?)));
+
+            let Some((header, frame)) = frame else {
+                // Resynchronise on the next frame header.
+                offset += 1;
+                continue;
+            };
+
+            offset += frame.len();
+            if !is_metadata_frame(header, frame) {
+                return Some((header, frame));
+            }
+        }
+
+        None
+    })
+}
+
+/// Builds an Info (constant bitrate) or Xing (variable bitrate) frame, so players can find the duration.
+fn xing_frame(first: Header, frame_count: u32, audio_len: usize, is_cbr: bool) -> Result<Vec<u8>> {
+    let mut header = first;
+
+    // Padding and a CRC would complicate the layout, so neither are used.
+    header.bytes[1] |= 1;
+    header.bytes[2] &= !0b10;
+
+    // Low bitrate frames can be too short for the header, so the bitrate is raised until it fits.
+    let xing_len = header.xing_offset() + 16;
+    while header.len() < xing_len {
+        header.bitrate_index += 1;
+        if header.bitrate_index == 15 {
+            anyhow::bail!("MPEG frames are too short for a Xing header");
+        }
+
+        header.bytes[2] = (header.bytes[2] & 0x0F) | ((header.bitrate_index as u8) << 4);
+    }
+
+    let mut frame = vec![0; header.len()];
+    frame[..4].copy_from_slice(&header.bytes);
+
+    let offset = header.xing_offset();
+    let file_len = u32::try_from(audio_len + frame.len())?;
+    frame[offset..offset + 4].copy_from_slice(if is_cbr { b"Info" } else { b"Xing" });
+    frame[offset + 4..offset + 8].copy_from_slice(&XING_FLAGS.to_be_bytes());
+    frame[offset + 8..offset + 12].copy_from_slice(&frame_count.to_be_bytes());
+    frame[offset + 12..offset + 16].copy_from_slice(&file_len.to_be_bytes());
+
+    Ok(frame)
+}
+
+/// Joins separately encoded MP3 files into a single stream.
+///
+/// Each file's tags and Xing headers are dropped, leaving only audio frames, which are given a new
+/// Info or Xing header covering the whole stream.
+pub fn join<T: AsRef<[u8]>>(files: &[T]) -> Result<Vec<u8>> {
+    let mut first = None;
+    let mut is_cbr = true;
+    let mut frame_count = 0_u32;
+    let mut audio = Vec::new();
+
+    for (header, frame) in files.iter().flat_map(|file| audio_frames(file.as_ref())) {
+        let first = *first.get_or_insert(header);
+        if header.version != first.version || header.sample_rate != first.sample_rate {
+            anyhow::bail!("MP3 files have different sample rates and cannot be joined");
+        }
+
+        is_cbr &= header.bitrate_index == first.bitrate_index;
+        frame_count += 1;
+        audio.extend_from_slice(frame);
+    }
+
+    let Some(first) = first else {
+        anyhow::bail!("MP3 files contain no audio frames");
+    };
+
+    let mut joined = xing_frame(first, frame_count, audio.len(), is_cbr)?;
+    joined.append(&mut audio);
+    Ok(joined)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{join, Header};
+
+    /// MPEG-1 Layer III, 128 kbps, 44100 Hz, stereo, without a CRC.
+    const HEADER: [u8; 4] = [0xFF, 0xFB, 0x90, 0x00];
+    const FRAME_LEN: usize = 417;
+    /// Offset of the Xing/Info tag in a stereo MPEG-1 frame.
+    const XING_OFFSET: usize = 36;
+
+    fn frame(header: [u8; 4], fill: u8) -> Vec<u8> {
+        let mut frame = vec![fill; Header::parse(&header).unwrap().len()];
+        frame[..4].copy_from_slice(&header);
+        frame
+    }
+
+    fn info_frame(frame_count: u32) -> Vec<u8> {
+        let mut info = frame(HEADER, 0);
+        info[XING_OFFSET..XING_OFFSET + 4].copy_from_slice(b"Info");
+        info[XING_OFFSET + 8..XING_OFFSET + 12].copy_from_slice(&frame_count.to_be_bytes());
+        info
+    }
+
+    fn id3v2() -> Vec<u8> {
+        let mut tag = b"ID3\x03\x00\x00\x00\x00\x00\x0A".to_vec();
+        tag.extend_from_slice(&[0x55; 10]);
+        tag
+    }
+
+    /// A file as gTTS returns it, with an `ID3v2` tag and an Info frame before the audio.
+    fn file(fills: &[u8]) -> Vec<u8> {
+        let mut file = id3v2();
+        file.extend(info_frame(fills.len() as u32));
+        for &fill in fills {
+            file.extend(frame(HEADER, fill));
+        }
+        file
+    }
+
+    fn frame_count(joined: &[u8]) -> u32 {
+        u32::from_be_bytes(
+            joined[XING_OFFSET + 8..XING_OFFSET + 12]
+                .try_into()
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn strips_tags_and_info_frames_from_every_file() {
+        let joined = join(&[file(&[1, 2, 3]), file(&[4, 5])]).unwrap();
+
+        let (info, audio) = joined.split_at(FRAME_LEN);
+        assert_eq!(&info[XING_OFFSET..XING_OFFSET + 4], b"Info");
+        assert_eq!(frame_count(&joined), 5);
+
+        let expected: Vec<u8> = [1, 2, 3, 4, 5]
+            .into_iter()
+            .flat_map(|fill| frame(HEADER, fill))
+            .collect();
+        assert_eq!(audio, expected);
+    }
+
+    #[test]
+    fn counts_frames_for_the_duration() {
+        let joined = join(&[file(&[1; 10]), file(&[2; 10])]).unwrap();
+        let duration = mp3_duration::from_read(&mut joined.as_slice()).unwrap();
+
+        // Each frame is 1152 samples at 44100 Hz.
+        assert_eq!(duration.as_micros(), 20 * 1152 * 1_000_000 / 44100);
+    }
+
+    #[test]
+    fn skips_id3v1_tags() {
+        let mut tagged = file(&[1]);
+        tagged.extend_from_slice(b"TAG");
+        tagged.extend_from_slice(&[0xFF; 125]);
+
+        let joined = join(&[tagged.clone(), tagged]).unwrap();
+        assert_eq!(frame_count(&joined), 2);
+        assert_eq!(joined.len(), 3 * FRAME_LEN);
+    }
+
+    #[test]
+    fn marks_mixed_bitrates_as_xing() {
+        let mut vbr = file(&[1]);
+        vbr.extend(frame([0xFF, 0xFB, 0xA0, 0x00], 2));
+
+        let joined = join(&[file(&[1]), vbr]).unwrap();
+        assert_eq!(&joined[XING_OFFSET..XING_OFFSET + 4], b"Xing");
+        assert_eq!(frame_count(&joined), 3);
+    }
+
+    #[test]
+    fn rejects_mixed_sample_rates() {
+        let mut other_rate = id3v2();
+        other_rate.extend(frame([0xFF, 0xFB, 0x94, 0x00], 1));
+
+        assert!(join(&[file(&[1]), other_rate]).is_err());
+        assert!(join::<&[u8]>(&[]).is_err());
+    }
+}
@@ -58,6 +58,29 @@ impl serde::Serialize for VoiceLocal {
     }
 }
 
+/// Checks the credentials are valid by listing a single page of voices.
+pub async fn check_ready(state: &State) -> Result<()> {
+    state.describe_voices().send().await?;
+    Ok(())
+}
+
+fn output_format(preferred_format: Option<&str>) -> OutputFormat {
+    match preferred_format.map(str::to_lowercase).as_deref() {
+        Some("mp3") => OutputFormat::Mp3,
+        Some("pcm") => OutputFormat::Pcm,
+        _ => OutputFormat::OggVorbis,
+    }
+}
+
+/// The content type of audio generated with `preferred_format`, with the layout of the raw PCM.
+pub fn content_type(preferred_format: Option<&str>) -> &'static str {
+    match output_format(preferred_format) {
+        OutputFormat::Mp3 => "audio/mpeg",
+        OutputFormat::Pcm => "audio/pcm; rate=16000; channels=1",
+        _ => "audio/ogg",
+    }
+}
+
 pub async fn get_tts(
     state: &State,
     mut text: String,
@@ -76,15 +99,7 @@ pub async fn get_tts(
         } else {
             TextType::Text
         }))
-        .set_output_format(
-            preferred_format
-                .and_then(|pf| match pf.to_lowercase().as_str() {
-                    "mp3" => Some(OutputFormat::Mp3),
-                    "pcm" => Some(OutputFormat::Pcm),
-                    _ => None,
-                })
-                .or(Some(OutputFormat::OggVorbis)),
-        )
+        .set_output_format(Some(output_format(preferred_format.as_deref())))
         .set_engine(Some(Engine::Standard))
         .set_voice_id(Some(voice.into()))
         .set_text(Some(text))
@@ -100,7 +115,7 @@ pub async fn get_tts(
 }
 
 static VOICES: tokio::sync::OnceCell<Vec<VoiceLocal>> = tokio::sync::OnceCell::const_new();
-async fn _get_voices(state: &State) -> Result<Vec<VoiceLocal>> {
+async fn fetch_voices(state: &State) -> Result<Vec<VoiceLocal>> {
     let mut voices = Vec::new();
     let mut next_token = None;
 
@@ -115,7 +130,7 @@ async fn _get_voices(state: &State) -> Result<Vec<VoiceLocal>> {
             voices.extend(v.into_iter().map(VoiceLocal::from).filter(|v| {
                 v.supported_engines
                     .as_ref()
-                    .map_or(false, |engines| engines.contains(&Engine::Standard))
+                    .is_some_and(|engines| engines.contains(&Engine::Standard))
             }));
         }
         if resp.next_token.is_none() {
@@ -128,14 +143,14 @@ async fn _get_voices(state: &State) -> Result<Vec<VoiceLocal>> {
 
 pub async fn check_voice(state: &State, voice: &str) -> Result<bool> {
     VOICES
-        .get_or_try_init(|| _get_voices(state))
+        .get_or_try_init(|| fetch_voices(state))
         .await
         .map(|voices| voices.iter().any(|s| s.id == Some(voice.into())))
 }
 
 pub async fn get_voices(state: &State) -> Result<Vec<String>> {
     VOICES
-        .get_or_try_init(|| _get_voices(state))
+        .get_or_try_init(|| fetch_voices(state))
         .await
         .map(|voices| {
             voices
@@ -148,5 +163,5 @@ pub async fn get_voices(state: &State) -> Result<Vec<String>> {
 }
 
 pub async fn get_raw_voices(state: &State) -> Result<&'static Vec<VoiceLocal>> {
-    VOICES.get_or_try_init(|| _get_voices(state)).await
+    VOICES.get_or_try_init(|| fetch_voices(state)).await
 }
@@ -0,0 +1,262 @@
+use std::{
+    future::Future,
+    os::unix::fs::FileTypeExt,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use arc_swap::ArcSwap;
+use hyper_util::{rt::TokioIo, server::conn::auto, service::TowerToHyperService};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::{config::TlsConfig, Result};
+
+/// How often the certificate and key files are checked for changes.
+const CERT_POLL_INTERVAL: Duration = Duration::from_secs(10);
+/// How long a client has to finish the TLS handshake before the connection is dropped.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+enum Listener {
+    Tcp(tokio::net::TcpListener),
+    Unix(tokio::net::UnixListener, PathBuf),
+}
+
+impl Listener {
+    /// Binds to `unix:{path}` as a Unix domain socket, or anything else as a TCP address.
+    async fn bind(bind_addr: &str) -> Result<Self> {
+        if let Some(path) = bind_addr.strip_prefix("unix:") {
+            // A previous run may have left the socket file behind, which would fail the bind.
+            // Anything else at the path is left alone, in case the path is a mistake.
+            match std::fs::symlink_metadata(path) {
+                Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+                Ok(_) => anyhow::bail!("{path} already exists and is not a socket"),
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+                Err(_) => {}
+            }
+
+            Ok(Self::Unix(
+                tokio::net::UnixListener::bind(path)?,
+                path.into(),
+            ))
+        } else {
+            Ok(Self::Tcp(tokio::net::TcpListener::bind(bind_addr).await?))
+        }
+    }
+
+    async fn accept(&self) -> std::io::Result<Box<dyn Io>> {
+        Ok(match self {
+            Self::Tcp(listener) => Box::new(listener.accept().await?.0),
+            Self::Unix(listener, _) => Box::new(listener.accept().await?.0),
+        })
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Self::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Serves the certificate loaded from disk, swapped out whenever the files change.
+struct CertResolver(ArcSwap<rustls::sign::CertifiedKey>);
+
+impl rustls::server::ResolvesServerCert for CertResolver {
+    fn resolve(
+        &self,
+        _client_hello: rustls::server::ClientHello<'_>,
+    ) -> Option<Arc<rustls::sign::CertifiedKey>> {
+        Some(self.0.load_full())
+    }
+}
+
+/// Loads a PEM certificate chain and private key, checking the key is usable.
+pub fn load_certified_key(config: &TlsConfig) -> Result<rustls::sign::CertifiedKey> {
+    let read_pem = |path: &str| {
+        let file = std::fs::File::open(path)
+            .map_err(|err| anyhow::anyhow!("Could not read {path}: {err}"))?;
+
+        rustls_pemfile::read_all(&mut std::io::BufReader::new(file))
+            .map_err(|err| anyhow::anyhow!("Could not parse {path}: {err}"))
+    };
+
+    let certs: Vec<_> = read_pem(&config.cert_path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            rustls_pemfile::Item::X509Certificate(cert) => Some(rustls::Certificate(cert)),
+            _ => None,
+        })
+        .collect();
+
+    if certs.is_empty() {
+        anyhow::bail!("No certificates found in {}", config.cert_path);
+    }
+
+    let key = read_pem(&config.key_path)?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(rustls::PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow::anyhow!("No private key found in {}", config.key_path))?;
+
+    let signing_key = rustls::sign::any_supported_type(&key)
+        .map_err(|err| anyhow::anyhow!("Unsupported private key in {}: {err}", config.key_path))?;
+
+    Ok(rustls::sign::CertifiedKey::new(certs, signing_key))
+}
+
+fn modified_times(config: &TlsConfig) -> [Option<SystemTime>; 2] {
+    [&config.cert_path, &config.key_path].map(|path| {
+        std::fs::metadata(path)
+            .and_then(|meta| meta.modified())
+            .ok()
+    })
+}
+
+/// Reloads the certificate when either file is modified, keeping the current one if loading fails.
+async fn watch_certificate(config: TlsConfig, resolver: Arc<CertResolver>) {
+    let mut last_modified = modified_times(&config);
+    let mut interval = tokio::time::interval(CERT_POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let modified = modified_times(&config);
+        if modified == last_modified {
+            continue;
+        }
+
+        last_modified = modified;
+        match load_certified_key(&config) {
+            Ok(certified_key) => {
+                resolver.0.store(Arc::new(certified_key));
+                tracing::info!("Reloaded TLS certificate from {}", config.cert_path);
+            }
+            Err(err) => {
+                tracing::error!("Failed to reload TLS certificate, keeping current one: {err:#}");
+            }
+        }
+    }
+}
+
+fn tls_acceptor(config: &TlsConfig) -> Result<tokio_rustls::TlsAcceptor> {
+    let resolver = Arc::new(CertResolver(ArcSwap::from_pointee(load_certified_key(
+        config,
+    )?)));
+
+    let mut server_config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver.clone());
+
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    tokio::spawn(watch_certificate(config.clone(), resolver));
+    Ok(tokio_rustls::TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// Serves `app` on `bind_addr` until `signal` resolves, then waits for open connections to finish.
+///
+/// `bind_addr` may be a TCP address or `unix:{path}`, and either can be wrapped in TLS.
+pub async fn serve(
+    bind_addr: &str,
+    tls: Option<&TlsConfig>,
+    app: axum::Router,
+    signal: impl Future<Output = ()>,
+) -> Result<()> {
+    let listener = Listener::bind(bind_addr).await?;
+    let acceptor = tls.map(tls_acceptor).transpose()?;
+
+    // Connections hold a receiver each, so `close_tx.closed()` resolves once they have all finished.
+    let (signal_tx, signal_rx) = tokio::sync::watch::channel(());
+    let (close_tx, close_rx) = tokio::sync::watch::channel(());
+
+    tokio::pin!(signal);
+    loop {
+        let io = tokio::select! {
+            result = listener.accept() => match result {
+                Ok(io) => io,
+                Err(err) => {
+                    // Usually running out of file descriptors, so back off instead of spinning.
+                    tracing::warn!("Failed to accept connection: {err}");
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            },
+            () = &mut signal => break,
+        };
+
+        let acceptor = acceptor.clone();
+        let app = app.clone();
+        let mut signal_rx = signal_rx.clone();
+        let close_rx = close_rx.clone();
+
+        tokio::spawn(async move {
+            let io: Box<dyn Io> = match acceptor {
+                Some(acceptor) => {
+                    // Clients that never finish the handshake would otherwise hold the task and delay shutdown.
+                    let handshake =
+                        tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(io));
+                    let result = tokio::select! {
+                        result = handshake => result,
+                        _ = signal_rx.changed() => return,
+                    };
+
+                    match result {
+                        Ok(Ok(io)) => Box::new(io),
+                        Ok(Err(err)) => {
+                            tracing::debug!("TLS handshake failed: {err}");
+                            return;
+                        }
+                        Err(_) => {
+                            tracing::debug!("TLS handshake timed out");
+                            return;
+                        }
+                    }
+                }
+                None => io,
+            };
+
+            serve_connection(io, app, signal_rx).await;
+            drop(close_rx);
+        });
+    }
+
+    drop(listener);
+    drop(close_rx);
+
+    signal_tx.send_replace(());
+    close_tx.closed().await;
+    Ok(())
+}
+
+async fn serve_connection(
+    io: Box<dyn Io>,
+    app: axum::Router,
+    mut signal_rx: tokio::sync::watch::Receiver<()>,
+) {
+    let builder = auto::Builder::new(hyper_util::rt::TokioExecutor::new());
+    let connection =
+        builder.serve_connection_with_upgrades(TokioIo::new(io), TowerToHyperService::new(app));
+
+    tokio::pin!(connection);
+    let result = tokio::select! {
+        result = connection.as_mut() => result,
+        _ = signal_rx.changed() => {
+            // Finishes any in-flight requests, then closes the connection.
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+    };
+
+    if let Err(err) = result {
+        tracing::debug!("Connection closed with error: {err}");
+    }
+}
@@ -0,0 +1,93 @@
+use axum::{extract::Request, http::HeaderValue, middleware::Next, response::Response};
+use rand::Rng;
+use sha2::Digest;
+use tracing::Instrument;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, Layer};
+
+use crate::{config::LogConfig, Result};
+
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// Sets up logging to stdout as text or JSON, and exporting spans over OTLP if `OTEL_EXPORTER_OTLP_ENDPOINT` is set.
+pub fn init(config: &LogConfig) -> Result<()> {
+    let filter = tracing_subscriber::EnvFilter::try_new(&config.level)?;
+    let fmt_layer = if config.format == "json" {
+        tracing_subscriber::fmt::layer()
+            .json()
+            .flatten_event(true)
+            .boxed()
+    } else {
+        tracing_subscriber::fmt::layer().boxed()
+    };
+
+    let otel_layer = if std::env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT").is_some() {
+        let resource = opentelemetry_sdk::Resource::new([opentelemetry::KeyValue::new(
+            "service.name",
+            env!("CARGO_PKG_NAME"),
+        )]);
+
+        // The endpoint is read from `OTEL_EXPORTER_OTLP_ENDPOINT` by the exporter itself.
+        let tracer = opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(opentelemetry_otlp::new_exporter().http())
+            .with_trace_config(opentelemetry_sdk::trace::config().with_resource(resource))
+            .install_batch(opentelemetry_sdk::runtime::Tokio)?;
+
+        Some(tracing_opentelemetry::layer().with_tracer(tracer))
+    } else {
+        None
+    };
+
+    tracing_subscriber::registry()
+        .with(fmt_layer)
+        .with(otel_layer)
+        .with(filter)
+        .init();
+
+    Ok(())
+}
+
+/// Describes message text for logs, replacing it with its length and a hash if `redact` is set.
+pub fn describe_text(text: &str, redact: bool) -> String {
+    if redact {
+        let hash = sha2::Sha256::digest(text);
+        let short_hash = u64::from_be_bytes(hash[..8].try_into().unwrap());
+        format!("<{} chars, sha256 {short_hash:016x}>", text.chars().count())
+    } else {
+        text.to_owned()
+    }
+}
+
+/// Flushes any spans that have not been exported yet.
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+/// Wraps each request in a span, tagged with the `X-Request-Id` header or a newly generated ID.
+///
+/// The ID is also returned in the `X-Request-Id` response header.
+pub async fn request_span(request: Request, next: Next) -> Response {
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .filter(|id| !id.is_empty() && id.len() <= 128)
+        .cloned()
+        .unwrap_or_else(|| {
+            let id: u128 = rand::thread_rng().gen();
+            HeaderValue::from_str(&format!("{id:032x}")).unwrap()
+        });
+
+    let span = tracing::info_span!(
+        "request",
+        request_id = request_id.to_str().unwrap_or_default(),
+        method = %request.method(),
+        path = request.uri().path(),
+        status = tracing::field::Empty,
+    );
+
+    let mut response = next.run(request).instrument(span.clone()).await;
+    span.record("status", response.status().as_u16());
+
+    response.headers_mut().insert(REQUEST_ID_HEADER, request_id);
+    response
+}
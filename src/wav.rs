@@ -0,0 +1,235 @@
+use std::{ops::Range, time::Duration};
+
+use crate::Result;
+
+/// The location of the samples in a RIFF/WAVE file, along with the `fmt ` fields needed to time them.
+pub struct Wav {
+    pub sample_rate: u32,
+    pub byte_rate: u32,
+    /// Bytes per sample across all channels.
+    pub block_align: u16,
+    /// The samples, running to the end of the file if the chunk size is unset or too large.
+    pub data: Range<usize>,
+    /// Offset of the `data` chunk's size field.
+    data_size_offset: usize,
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        bytes.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        bytes.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+/// Walks the chunks of a RIFF/WAVE file until the `data` chunk, which must come after `fmt `.
+///
+/// Other chunks, such as `LIST` or `fact`, are skipped over.
+pub fn parse(wav: &[u8]) -> Result<Wav> {
+    if wav.get(0..4) != Some(b"RIFF") || wav.get(8..12) != Some(b"WAVE") {
+        anyhow::bail!("Not a RIFF/WAVE file");
+    }
+
+    let mut format = None;
+    let mut offset = 12;
+    loop {
+        let (Some(id), Some(size)) = (wav.get(offset..offset + 4), read_u32(wav, offset + 4))
+        else {
+            anyhow::bail!("WAV file has no data chunk");
+        };
+
+        let body = offset + 8;
+        match id {
+            b"fmt " => {
+                let fmt = wav
+                    .get(body..body + 16)
+                    .ok_or_else(|| anyhow::anyhow!("WAV fmt chunk is truncated"))?;
+
+                format = Some((
+                    read_u32(fmt, 4).unwrap(),
+                    read_u32(fmt, 8).unwrap(),
+                    read_u16(fmt, 12).unwrap(),
+                ));
+            }
+            b"data" => {
+                let (sample_rate, byte_rate, block_align) =
+                    format.ok_or_else(|| anyhow::anyhow!("WAV data comes before fmt"))?;
+
+                // Streamed WAV files leave the size unset, so it cannot be trusted.
+                let end = usize::try_from(size)
+                    .ok()
+                    .and_then(|size| body.checked_add(size))
+                    .filter(|end| *end <= wav.len())
+                    .unwrap_or(wav.len());
+
+                return Ok(Wav {
+                    sample_rate,
+                    byte_rate,
+                    block_align,
+                    data: body..end,
+                    data_size_offset: offset + 4,
+                });
+            }
+            _ => {}
+        }
+
+        // Chunks are padded to an even length.
+        let size = usize::try_from(size)?;
+        offset = body
+            .checked_add(size + (size % 2))
+            .ok_or_else(|| anyhow::anyhow!("WAV chunk size overflowed"))?;
+    }
+}
+
+impl Wav {
+    pub fn duration(&self) -> Result<Duration> {
+        if self.byte_rate == 0 {
+            anyhow::bail!("WAV file has a byte rate of 0");
+        }
+
+        // A trailing partial sample cannot be played.
+        let block_align = usize::from(self.block_align.max(1));
+        let data_len = self.data.len() / block_align * block_align;
+
+        #[allow(clippy::cast_precision_loss)]
+        let secs = data_len as f64 / f64::from(self.byte_rate);
+        Ok(Duration::from_secs_f64(secs))
+    }
+}
+
+/// Sets the RIFF and `data` chunk sizes to match the file, for WAV files written to a pipe.
+///
+/// Assumes the `data` chunk runs to the end of the file, as it does for streamed output.
+pub fn fix_sizes(wav: &mut [u8]) -> Result<()> {
+    let parsed = parse(wav)?;
+    let riff_size = u32::try_from(wav.len() - 8)?;
+    let data_size = u32::try_from(wav.len() - parsed.data.start)?;
+
+    wav[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    wav[parsed.data_size_offset..parsed.data_size_offset + 4]
+        .copy_from_slice(&data_size.to_le_bytes());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{fix_sizes, parse};
+
+    /// 16-bit mono PCM at 8000 Hz.
+    const FMT: [u8; 16] = [1, 0, 1, 0, 0x40, 0x1F, 0, 0, 0x80, 0x3E, 0, 0, 2, 0, 16, 0];
+
+    fn chunk(id: [u8; 4], size: u32, body: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend_from_slice(&size.to_le_bytes());
+        chunk.extend_from_slice(body);
+        chunk
+    }
+
+    fn riff(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let body = chunks.concat();
+        let mut wav = chunk(*b"RIFF", u32::try_from(body.len() + 4).unwrap(), b"WAVE");
+        wav.extend_from_slice(&body);
+        wav
+    }
+
+    #[test]
+    fn parses_canonical_header() {
+        let wav = riff(&[chunk(*b"fmt ", 16, &FMT), chunk(*b"data", 4, &[0; 4])]);
+        let parsed = parse(&wav).unwrap();
+
+        assert_eq!(parsed.sample_rate, 8000);
+        assert_eq!(parsed.data, 44..48);
+        assert_eq!(parsed.duration().unwrap(), Duration::from_micros(250));
+    }
+
+    #[test]
+    fn times_mulaw_by_its_byte_rate() {
+        // gCloud returns MULAW and ALAW in a WAV container, with one byte per sample at 8 kHz.
+        let mulaw = [7, 0, 1, 0, 0x40, 0x1F, 0, 0, 0x40, 0x1F, 0, 0, 1, 0, 8, 0];
+        let wav = riff(&[
+            chunk(*b"fmt ", 16, &mulaw),
+            chunk(*b"data", 8000, &[0xFF; 8000]),
+        ]);
+
+        assert_eq!(
+            parse(&wav).unwrap().duration().unwrap(),
+            Duration::from_secs(1)
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_headers() {
+        let full = riff(&[chunk(*b"fmt ", 16, &FMT), chunk(*b"data", 4, &[0; 4])]);
+        for len in [0, 4, 11, 12, 20, 30, 36, 40] {
+            assert!(parse(&full[..len]).is_err(), "parsed {len} bytes");
+        }
+
+        let short_fmt = riff(&[chunk(*b"fmt ", 16, &FMT[..10])]);
+        let err = parse(&short_fmt).err().unwrap();
+        assert_eq!(err.to_string(), "WAV fmt chunk is truncated");
+    }
+
+    #[test]
+    fn skips_extended_fmt_chunks() {
+        // WAVE_FORMAT_EXTENSIBLE adds a 2 byte extension size and 22 bytes of extension.
+        let mut extensible = FMT.to_vec();
+        extensible.extend_from_slice(&22_u16.to_le_bytes());
+        extensible.extend_from_slice(&[0; 22]);
+
+        let wav = riff(&[
+            chunk(*b"fmt ", 40, &extensible),
+            chunk(*b"data", 2, &[0; 2]),
+        ]);
+        let parsed = parse(&wav).unwrap();
+
+        assert_eq!(parsed.byte_rate, 16000);
+        assert_eq!(parsed.data, 68..70);
+    }
+
+    #[test]
+    fn skips_padding_after_odd_sized_chunks() {
+        let wav = riff(&[
+            chunk(*b"fmt ", 16, &FMT),
+            chunk(*b"LIST", 3, &[1, 2, 3, 0]),
+            chunk(*b"data", 2, &[0; 2]),
+        ]);
+
+        assert_eq!(parse(&wav).unwrap().data, 56..58);
+    }
+
+    #[test]
+    fn finds_empty_data_after_extra_chunks() {
+        // A failed mbrola run can still write a header, which is longer than 44 bytes with extra chunks.
+        let wav = riff(&[
+            chunk(*b"fmt ", 16, &FMT),
+            chunk(*b"LIST", 4, b"INFO"),
+            chunk(*b"fact", 4, &[0; 4]),
+            chunk(*b"data", u32::MAX, &[]),
+        ]);
+        assert!(wav.len() > 44);
+
+        let parsed = parse(&wav).unwrap();
+        assert!(parsed.data.is_empty());
+        assert_eq!(parsed.duration().unwrap(), Duration::ZERO);
+    }
+
+    #[test]
+    fn fixes_streamed_sizes() {
+        let mut wav = riff(&[
+            chunk(*b"fmt ", 16, &FMT),
+            chunk(*b"data", u32::MAX, &[0; 6]),
+        ]);
+        assert_eq!(parse(&wav).unwrap().data, 44..50);
+
+        fix_sizes(&mut wav).unwrap();
+        assert_eq!(wav[4..8], 42_u32.to_le_bytes());
+        assert_eq!(wav[40..44], 6_u32.to_le_bytes());
+    }
+}